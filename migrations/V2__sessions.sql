@@ -0,0 +1,10 @@
+CREATE TABLE sessions
+(
+    session_id uuid PRIMARY KEY,
+    userid uuid NOT NULL REFERENCES users (userid) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    revoked boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX sessions_userid_idx ON sessions (userid);
@@ -0,0 +1,6 @@
+CREATE TABLE collaborators
+(
+    repo_id uuid NOT NULL REFERENCES repositories (repo_id) ON DELETE CASCADE,
+    userid uuid NOT NULL REFERENCES users (userid) ON DELETE CASCADE,
+    PRIMARY KEY (repo_id, userid)
+);
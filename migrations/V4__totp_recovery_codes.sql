@@ -0,0 +1,9 @@
+CREATE TABLE totp_recovery_codes
+(
+    code_id uuid PRIMARY KEY,
+    userid uuid NOT NULL REFERENCES users (userid) ON DELETE CASCADE,
+    code_hash text NOT NULL,
+    used_at timestamptz
+);
+
+CREATE INDEX totp_recovery_codes_userid_idx ON totp_recovery_codes (userid);
@@ -0,0 +1,14 @@
+CREATE TABLE access_tokens
+(
+    token_id uuid PRIMARY KEY,
+    userid uuid NOT NULL REFERENCES users (userid) ON DELETE CASCADE,
+    token_name text NOT NULL,
+    token_hash text NOT NULL,
+    scopes text[] NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    last_used_at timestamptz,
+    revoked boolean NOT NULL DEFAULT false
+);
+
+CREATE INDEX access_tokens_userid_idx ON access_tokens (userid);
@@ -0,0 +1 @@
+CREATE UNIQUE INDEX repositories_owner_id_repo_name_idx ON repositories (owner_id, repo_name);
@@ -0,0 +1,7 @@
+CREATE TABLE pending_sign_ins
+(
+    pending_id uuid PRIMARY KEY,
+    userid uuid NOT NULL REFERENCES users (userid) ON DELETE CASCADE,
+    expires_at timestamptz NOT NULL,
+    attempts integer NOT NULL DEFAULT 0
+);
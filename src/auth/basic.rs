@@ -0,0 +1,33 @@
+use rocket::Request;
+
+use crate::cgi::auth::Auth;
+
+/// Credentials sent in an `Authorization: Basic ...` header.
+#[derive(Clone, Debug)]
+pub struct BasicCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl BasicCredentials {
+    pub fn from_request(request: &Request<'_>) -> Option<Self> {
+        Self::parse(request.headers().get_one("Authorization")?)
+    }
+
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().splitn(2, ' ');
+        match parts.next()?.parse::<Auth>() {
+            Ok(Auth::Basic) => {}
+            _ => return None,
+        }
+        let decoded = base64::decode(parts.next()?.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let username = parts.next()?.to_string();
+        let password = parts.next()?.to_string();
+        Some(Self { username, password })
+    }
+}
+
+/// Value of the `WWW-Authenticate` header sent along with a 401 response.
+pub const CHALLENGE: &str = r#"Basic realm="sourceshack", charset="UTF-8""#;
@@ -0,0 +1,7 @@
+pub mod basic;
+pub mod password;
+pub mod session;
+pub mod token;
+pub mod totp;
+
+pub use session::CurrentUser;
@@ -0,0 +1,76 @@
+use log::error;
+use password_hash::{HasherError, PasswordHash, PasswordHasher, Salt};
+use pbkdf2::Pbkdf2;
+use rocket::http::Status;
+use sqlx::types::Uuid;
+
+use crate::db::Postgres;
+
+pub fn hash_password<'a>(password: &[u8], salt: Salt<'a>) -> Result<PasswordHash<'a>, HasherError> {
+    Pbkdf2.hash_password(
+        password,
+        None,
+        None,
+        pbkdf2::Params {
+            rounds: 10_000,
+            output_length: 32,
+        },
+        salt,
+    )
+}
+
+pub fn verify_password(password: &[u8], password_hash: &str) -> Result<bool, HasherError> {
+    let password_hash = PasswordHash::new(password_hash)?;
+    let salt = match password_hash.salt {
+        Some(salt) => salt,
+        None => return Ok(false),
+    };
+    let login_hash = hash_password(password, salt)?;
+    Ok(login_hash.hash == password_hash.hash)
+}
+
+/// Looks up the user identified by `login` (a username or an email address) and checks
+/// `password` against it. Returns the user's id and username, or `None` if there is no such
+/// user or the password is wrong.
+pub async fn check_credentials<'r>(
+    pg: Postgres<'r>,
+    login: &str,
+    password: &str,
+) -> Result<Option<(Uuid, String)>, Status> {
+    let emails: &[&str] = &[login];
+    let query_result = sqlx::query!(
+        r#"
+        SELECT
+            userid, username, password_hash
+        FROM (
+            SELECT
+                userid, username, emails, password_hash
+            FROM
+                public.users
+            WHERE
+                username = $1 OR $2 IN(emails)
+        ) as subquery"#,
+        login,
+        emails,
+    )
+    .fetch_optional(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for user: {}", err);
+        Status::InternalServerError
+    })?;
+
+    if let Some(user) = query_result {
+        let is_valid = verify_password(password.as_bytes(), &user.password_hash).map_err(|err| {
+            error!("Could not verify password of {}: {:?}", user.userid, err);
+            Status::InternalServerError
+        })?;
+        Ok(if is_valid {
+            Some((user.userid, user.username))
+        } else {
+            None
+        })
+    } else {
+        Ok(None)
+    }
+}
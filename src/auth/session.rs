@@ -0,0 +1,285 @@
+use log::error;
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    outcome::try_outcome,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use sqlx::types::Uuid;
+
+use crate::db::Postgres;
+
+/// Name of the private cookie holding the session id.
+pub const SESSION_COOKIE: &str = "session";
+
+/// Name of the private cookie remembering who entered a correct password but still has to pass
+/// the second sign-in step.
+pub const PENDING_SIGN_IN_COOKIE: &str = "pending_sign_in";
+
+/// How long a session stays valid after signing in.
+const SESSION_LIFETIME_DAYS: i32 = 30;
+
+/// How long the second sign-in step may take before the password has to be entered again.
+const PENDING_SIGN_IN_LIFETIME_MINUTES: i32 = 5;
+
+/// How many codes may be tried in the second sign-in step before the password has to be entered
+/// again.
+const MAX_SECOND_FACTOR_ATTEMPTS: i32 = 5;
+
+/// The user that is signed in through the session cookie of the current request.
+///
+/// Requests without a valid, unexpired and unrevoked session are forwarded, so routes that
+/// work for anonymous visitors should take an `Option<CurrentUser>`.
+#[derive(Clone, Debug)]
+pub struct CurrentUser {
+    pub userid: Uuid,
+    pub username: String,
+    pub session_id: Uuid,
+}
+
+impl CurrentUser {
+    /// The user this guard already found for `request`, if any. Responders can't run async
+    /// guards, so this is how error pages know who is signed in.
+    pub fn cached(request: &Request<'_>) -> Option<Self> {
+        request.local_cache(|| None::<Self>).clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for CurrentUser {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let session_id = match request
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| Uuid::parse_str(cookie.value()).ok())
+        {
+            Some(session_id) => session_id,
+            None => return Outcome::Forward(()),
+        };
+        let pg = try_outcome!(request.guard::<Postgres<'r>>().await);
+
+        let query_result = sqlx::query!(
+            r#"
+            SELECT
+                users.userid, users.username
+            FROM
+                public.sessions
+            INNER JOIN
+                public.users ON users.userid = sessions.userid
+            WHERE
+                sessions.session_id = $1
+                AND NOT sessions.revoked
+                AND sessions.expires_at > now()
+            "#,
+            session_id,
+        )
+        .fetch_optional(pg)
+        .await;
+
+        match query_result {
+            Ok(Some(user)) => {
+                let user = Self {
+                    userid: user.userid,
+                    username: user.username,
+                    session_id,
+                };
+                request.local_cache(|| Some(user.clone()));
+                Outcome::Success(user)
+            }
+            Ok(None) => {
+                request.cookies().remove_private(Cookie::named(SESSION_COOKIE));
+                Outcome::Forward(())
+            }
+            Err(err) => {
+                error!("Could not query for session: {}", err);
+                Outcome::Failure((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+/// Creates a new session for `userid` and hands its id to the client in a private cookie.
+pub async fn start_session<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    userid: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    let session_id = sqlx::query!(
+        r#"
+        INSERT INTO public.sessions
+            (session_id, userid, expires_at)
+        VALUES
+            (gen_random_uuid(), $1, now() + make_interval(days => $2))
+        RETURNING
+            session_id"#,
+        userid,
+        SESSION_LIFETIME_DAYS,
+    )
+    .fetch_one(pg)
+    .await?
+    .session_id;
+
+    cookies.add_private(
+        Cookie::build(SESSION_COOKIE, session_id.to_string())
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::days(SESSION_LIFETIME_DAYS.into()))
+            .finish(),
+    );
+
+    Ok(session_id)
+}
+
+/// Revokes a single session and removes the session cookie.
+pub async fn end_session<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    session_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+    sqlx::query!(
+        r#"
+        UPDATE public.sessions
+        SET revoked = true
+        WHERE session_id = $1"#,
+        session_id,
+    )
+    .execute(pg)
+    .await?;
+    Ok(())
+}
+
+/// Revokes every session belonging to `userid`, signing the user out on all devices.
+pub async fn revoke_all_sessions<'r>(pg: Postgres<'r>, userid: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE public.sessions
+        SET revoked = true
+        WHERE userid = $1 AND NOT revoked"#,
+        userid,
+    )
+    .execute(pg)
+    .await?;
+    Ok(())
+}
+
+/// Remembers that `userid` entered a correct password and now has to provide a second factor.
+pub async fn start_pending_sign_in<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    userid: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM public.pending_sign_ins
+        WHERE expires_at <= now()"#,
+    )
+    .execute(pg)
+    .await?;
+    let pending_id = sqlx::query!(
+        r#"
+        INSERT INTO public.pending_sign_ins
+            (pending_id, userid, expires_at)
+        VALUES
+            (gen_random_uuid(), $1, now() + make_interval(mins => $2))
+        RETURNING
+            pending_id"#,
+        userid,
+        PENDING_SIGN_IN_LIFETIME_MINUTES,
+    )
+    .fetch_one(pg)
+    .await?
+    .pending_id;
+
+    cookies.add_private(
+        Cookie::build(PENDING_SIGN_IN_COOKIE, pending_id.to_string())
+            .path("/sign-in")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .max_age(time::Duration::minutes(PENDING_SIGN_IN_LIFETIME_MINUTES.into()))
+            .finish(),
+    );
+    Ok(())
+}
+
+fn pending_sign_in_id(cookies: &CookieJar<'_>) -> Option<Uuid> {
+    let cookie = cookies.get_private(PENDING_SIGN_IN_COOKIE)?;
+    Uuid::parse_str(cookie.value()).ok()
+}
+
+/// Returns whether there is a sign-in waiting for a second factor that may still be attempted.
+pub async fn has_pending_sign_in<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+) -> Result<bool, sqlx::Error> {
+    let pending_id = match pending_sign_in_id(cookies) {
+        Some(pending_id) => pending_id,
+        None => return Ok(false),
+    };
+    Ok(sqlx::query!(
+        r#"
+        SELECT
+            pending_id
+        FROM
+            public.pending_sign_ins
+        WHERE
+            pending_id = $1 AND expires_at > now() AND attempts < $2"#,
+        pending_id,
+        MAX_SECOND_FACTOR_ATTEMPTS,
+    )
+    .fetch_optional(pg)
+    .await?
+    .is_some())
+}
+
+/// Counts an attempt at the second sign-in step and returns the user it is for, or `None` if
+/// there is no pending sign-in or it has expired or run out of attempts.
+///
+/// Attempts are counted before the code is checked, so concurrent requests can't get past the
+/// limit either.
+pub async fn attempt_pending_sign_in<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let pending_id = match pending_sign_in_id(cookies) {
+        Some(pending_id) => pending_id,
+        None => return Ok(None),
+    };
+    let attempt = sqlx::query!(
+        r#"
+        UPDATE public.pending_sign_ins
+        SET attempts = attempts + 1
+        WHERE pending_id = $1 AND expires_at > now() AND attempts < $2
+        RETURNING
+            userid"#,
+        pending_id,
+        MAX_SECOND_FACTOR_ATTEMPTS,
+    )
+    .fetch_optional(pg)
+    .await?;
+    if attempt.is_none() {
+        end_pending_sign_in(pg, cookies).await?;
+    }
+    Ok(attempt.map(|attempt| attempt.userid))
+}
+
+pub async fn end_pending_sign_in<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+) -> Result<(), sqlx::Error> {
+    if let Some(pending_id) = pending_sign_in_id(cookies) {
+        sqlx::query!(
+            r#"
+            DELETE FROM public.pending_sign_ins
+            WHERE pending_id = $1"#,
+            pending_id,
+        )
+        .execute(pg)
+        .await?;
+    }
+    cookies.remove_private(Cookie::build(PENDING_SIGN_IN_COOKIE, "").path("/sign-in").finish());
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use std::{fmt, str::FromStr};
+
+use log::error;
+use password_hash::SaltString;
+use rand_core::{OsRng, RngCore};
+use rocket::{
+    http::Status,
+    outcome::try_outcome,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use crate::{
+    auth::{
+        basic::BasicCredentials,
+        password::{hash_password, verify_password},
+        CurrentUser,
+    },
+    db::Postgres,
+};
+
+/// Every personal access token starts with this, which makes it easy to tell a token apart
+/// from a password and to find leaked tokens.
+const TOKEN_PREFIX: &str = "sspat_";
+
+/// Number of random bytes in the secret part of a token.
+const TOKEN_SECRET_LEN: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Fetching and cloning over HTTP.
+    RepoRead,
+    /// Pushing over HTTP.
+    RepoWrite,
+    /// Calling the routes that take an `ApiUser`, e.g. creating repositories.
+    Api,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::RepoRead => "repo:read",
+            Scope::RepoWrite => "repo:write",
+            Scope::Api => "api",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ScopeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repo:read" => Ok(Self::RepoRead),
+            "repo:write" => Ok(Self::RepoWrite),
+            "api" => Ok(Self::Api),
+            _ => Err(ScopeParseError { _priv: () }),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct ScopeParseError {
+    _priv: (),
+}
+
+/// The user a request acts for, either through the session cookie or through an access token
+/// with the `api` scope sent as the password of an `Authorization: Basic` header. Routes that
+/// scripts are meant to call take this instead of a `CurrentUser`.
+///
+/// Passwords are not accepted, as they would skip the second sign-in step.
+#[derive(Clone, Debug)]
+pub struct ApiUser {
+    pub userid: Uuid,
+    pub username: String,
+}
+
+#[async_trait::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ApiUser {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let credentials = match BasicCredentials::from_request(request) {
+            Some(credentials) => credentials,
+            None => {
+                let user = try_outcome!(request.guard::<CurrentUser>().await);
+                return Outcome::Success(Self {
+                    userid: user.userid,
+                    username: user.username,
+                });
+            }
+        };
+        if !looks_like_token(&credentials.password) {
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+        let pg = try_outcome!(request.guard::<Postgres<'r>>().await);
+        match authenticate(pg, &credentials.username, &credentials.password).await {
+            Ok(Some((userid, scopes))) if scopes.contains(&Scope::Api) => Outcome::Success(Self {
+                userid,
+                username: credentials.username,
+            }),
+            Ok(Some(_)) => Outcome::Failure((Status::Forbidden, ())),
+            Ok(None) => Outcome::Failure((Status::Unauthorized, ())),
+            Err(status) => Outcome::Failure((status, ())),
+        }
+    }
+}
+
+/// A token as listed in the account settings. The secret itself is never stored.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AccessToken {
+    pub token_id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub expires_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// Creates a new token for `userid` and returns it in plain text. It cannot be recovered
+/// afterwards, so it has to be shown to the user right away.
+pub async fn create<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+    name: &str,
+    scopes: &[Scope],
+    expires_in_days: i32,
+) -> Result<String, Status> {
+    let mut secret = [0; TOKEN_SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    let secret = base32::encode(base32::Alphabet::Crockford, &secret).to_lowercase();
+    let salt = SaltString::generate(OsRng::default());
+    let secret_hash = hash_password(secret.as_bytes(), salt.as_salt()).map_err(|err| {
+        error!("Could not hash access token: {:?}", err);
+        Status::InternalServerError
+    })?;
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+
+    let token_id = sqlx::query!(
+        r#"
+        INSERT INTO public.access_tokens
+            (token_id, userid, token_name, token_hash, scopes, expires_at)
+        VALUES
+            (gen_random_uuid(), $1, $2, $3, $4, now() + make_interval(days => $5))
+        RETURNING
+            token_id"#,
+        userid,
+        name,
+        format!("{}", secret_hash),
+        &scopes,
+        expires_in_days,
+    )
+    .fetch_one(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not create access token for {}: {}", userid, err);
+        Status::InternalServerError
+    })?
+    .token_id;
+
+    Ok(format!(
+        "{}{}_{}",
+        TOKEN_PREFIX,
+        token_id.to_simple(),
+        secret
+    ))
+}
+
+/// Lists the tokens of `userid` that have neither been revoked nor expired.
+pub async fn list_for_userid<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+) -> Result<Vec<AccessToken>, Status> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT
+            token_id,
+            token_name,
+            scopes,
+            to_char(created_at, 'YYYY-MM-DD') AS "created_at!",
+            to_char(expires_at, 'YYYY-MM-DD') AS "expires_at!",
+            to_char(last_used_at, 'YYYY-MM-DD') AS last_used_at
+        FROM
+            public.access_tokens
+        WHERE
+            userid = $1 AND NOT revoked AND expires_at > now()
+        ORDER BY
+            created_at DESC"#,
+        userid,
+    )
+    .fetch_all(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for access tokens of {}: {}", userid, err);
+        Status::InternalServerError
+    })?
+    .into_iter()
+    .map(|token| AccessToken {
+        token_id: token.token_id.to_string(),
+        name: token.token_name,
+        scopes: token.scopes,
+        created_at: token.created_at,
+        expires_at: token.expires_at,
+        last_used_at: token.last_used_at,
+    })
+    .collect())
+}
+
+pub async fn revoke<'r>(pg: Postgres<'r>, userid: Uuid, token_id: Uuid) -> Result<(), Status> {
+    sqlx::query!(
+        r#"
+        UPDATE public.access_tokens
+        SET revoked = true
+        WHERE token_id = $1 AND userid = $2"#,
+        token_id,
+        userid,
+    )
+    .execute(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not revoke access token {}: {}", token_id, err);
+        Status::InternalServerError
+    })?;
+    Ok(())
+}
+
+pub fn looks_like_token(password: &str) -> bool {
+    password.starts_with(TOKEN_PREFIX)
+}
+
+/// Checks `token` against the tokens of the user called `username`. Returns the user's id and
+/// the token's scopes if it is valid, unexpired and unrevoked.
+pub async fn authenticate<'r>(
+    pg: Postgres<'r>,
+    username: &str,
+    token: &str,
+) -> Result<Option<(Uuid, Vec<Scope>)>, Status> {
+    let (token_id, secret) = match parse(token) {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+
+    let query_result = sqlx::query!(
+        r#"
+        SELECT
+            access_tokens.userid, access_tokens.token_hash, access_tokens.scopes
+        FROM
+            public.access_tokens
+        INNER JOIN
+            public.users ON users.userid = access_tokens.userid
+        WHERE
+            access_tokens.token_id = $1
+            AND users.username = $2
+            AND NOT access_tokens.revoked
+            AND access_tokens.expires_at > now()
+        "#,
+        token_id,
+        username,
+    )
+    .fetch_optional(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for access token: {}", err);
+        Status::InternalServerError
+    })?;
+
+    let token = match query_result {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+    let is_valid = verify_password(secret.as_bytes(), &token.token_hash).map_err(|err| {
+        error!("Could not verify access token {}: {:?}", token_id, err);
+        Status::InternalServerError
+    })?;
+    if !is_valid {
+        return Ok(None);
+    }
+
+    if let Err(err) = sqlx::query!(
+        r#"
+        UPDATE public.access_tokens
+        SET last_used_at = now()
+        WHERE token_id = $1"#,
+        token_id,
+    )
+    .execute(pg)
+    .await
+    {
+        error!("Could not update last use of access token {}: {}", token_id, err);
+    }
+
+    let scopes = token
+        .scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect();
+    Ok(Some((token.userid, scopes)))
+}
+
+fn parse(token: &str) -> Option<(Uuid, &str)> {
+    let token = token.strip_prefix(TOKEN_PREFIX)?;
+    let mut parts = token.splitn(2, '_');
+    let token_id = Uuid::parse_str(parts.next()?).ok()?;
+    let secret = parts.next()?;
+    Some((token_id, secret))
+}
@@ -0,0 +1,359 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use password_hash::{HasherError, SaltString};
+use qrcode::{render::svg, QrCode};
+use rand_core::{OsRng, RngCore};
+use sha1::Sha1;
+use sqlx::types::Uuid;
+
+use crate::{
+    auth::password::{hash_password, verify_password},
+    db::Postgres,
+};
+
+/// Name of the private cookie holding a secret that has been generated but not yet confirmed.
+pub const ENROLMENT_COOKIE: &str = "totp_enrolment";
+
+/// Number of bytes in a generated secret. RFC 4226 recommends 160 bits.
+const SECRET_LEN: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD_SECONDS: u64 = 30;
+/// How many periods before and after the current one are accepted to tolerate clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+
+/// Number of recovery codes handed out when two-factor authentication is enabled.
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 5;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LEN];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Encodes `secret` the way authenticator apps expect it to be typed in.
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(ALPHABET, secret)
+}
+
+pub fn decode_secret(encoded: &str) -> Option<Vec<u8>> {
+    base32::decode(ALPHABET, encoded)
+}
+
+/// Builds the `otpauth://` URI understood by authenticator apps.
+///
+/// `username` is expected to be a valid `AaudStr`, so it needs no escaping.
+pub fn otpauth_uri(username: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/sourceshack:{}?secret={}&issuer=sourceshack&algorithm=SHA1&digits={}&period={}",
+        username,
+        encode_secret(secret),
+        DIGITS,
+        PERIOD_SECONDS,
+    )
+}
+
+/// Renders `uri` as an inline SVG QR code.
+pub fn qr_code_svg(uri: &str) -> Option<String> {
+    QrCode::new(uri.as_bytes()).ok().map(|code| {
+        code.render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build()
+    })
+}
+
+/// Checks `code` against the TOTP generated from `secret` for the current time and returns the
+/// time step it belongs to.
+///
+/// A valid code stays valid for a while, so use `use_code` once two-factor authentication is
+/// enabled to make sure that every code is accepted only once.
+pub fn verify_code(secret: &[u8], code: &str) -> Option<u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    verify_code_at(secret, code, now)
+}
+
+fn verify_code_at(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let current_step = (unix_time / PERIOD_SECONDS) as i64;
+    // Every step in the window is checked, so that the time taken doesn't tell which one matched.
+    let mut matching_step = None;
+    for step in (current_step - ALLOWED_DRIFT)..=(current_step + ALLOWED_DRIFT) {
+        if step < 0 {
+            continue;
+        }
+        let expected = format!("{:01$}", hotp(secret, step as u64), DIGITS as usize);
+        if constant_time_eq(expected.as_bytes(), code.as_bytes()) {
+            matching_step = Some(step as u64);
+        }
+    }
+    matching_step
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Checks `code` like `verify_code` and records its time step for `userid`, rejecting codes
+/// from the same or an earlier step than the last accepted one.
+pub async fn use_code<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+    secret: &[u8],
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let step = match verify_code(secret, code) {
+        Some(step) => step as i64,
+        None => return Ok(false),
+    };
+    let updated = sqlx::query!(
+        r#"
+        UPDATE public.users
+        SET totp_last_used_step = $2
+        WHERE
+            userid = $1
+            AND (totp_last_used_step IS NULL OR totp_last_used_step < $2)"#,
+        userid,
+        step,
+    )
+    .execute(pg)
+    .await?;
+    Ok(updated.rows_affected() == 1)
+}
+
+/// RFC 4226 HOTP value of `secret` at `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Generates a fresh set of recovery codes, returned in plain text along with their hashes.
+///
+/// The plain text codes are meant to be shown to the user once and then forgotten.
+pub fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), HasherError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut code_hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let mut code = [0; RECOVERY_CODE_LEN];
+        OsRng.fill_bytes(&mut code);
+        let code = encode_secret(&code).to_lowercase();
+        let salt = SaltString::generate(OsRng::default());
+        code_hashes.push(format!("{}", hash_password(code.as_bytes(), salt.as_salt())?));
+        codes.push(code);
+    }
+    Ok((codes, code_hashes))
+}
+
+/// Stores `secret` for `userid` and replaces any existing recovery codes with `code_hashes`.
+///
+/// `step` is the time step of the code that confirmed the secret, which may not be used again.
+pub async fn enable<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+    secret: &[u8],
+    step: u64,
+    code_hashes: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pg.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE public.users
+        SET totp_secret = $2, totp_last_used_step = $3
+        WHERE userid = $1"#,
+        userid,
+        secret,
+        step as i64,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM public.totp_recovery_codes
+        WHERE userid = $1"#,
+        userid,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO public.totp_recovery_codes
+            (code_id, userid, code_hash)
+        SELECT
+            gen_random_uuid(), $1, code_hash
+        FROM
+            UNNEST($2::text[]) AS code_hash"#,
+        userid,
+        code_hashes,
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Removes the secret and all recovery codes of `userid`.
+pub async fn disable<'r>(pg: Postgres<'r>, userid: Uuid) -> Result<(), sqlx::Error> {
+    let mut tx = pg.begin().await?;
+    sqlx::query!(
+        r#"
+        UPDATE public.users
+        SET totp_secret = NULL, totp_last_used_step = NULL
+        WHERE userid = $1"#,
+        userid,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM public.totp_recovery_codes
+        WHERE userid = $1"#,
+        userid,
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await
+}
+
+pub async fn secret_for_userid<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT
+            totp_secret
+        FROM
+            public.users
+        WHERE
+            userid = $1"#,
+        userid,
+    )
+    .fetch_optional(pg)
+    .await?
+    .and_then(|user| user.totp_secret))
+}
+
+/// Checks `code` against the unused recovery codes of `userid` and marks it as used if it
+/// matches, so that every recovery code works only once.
+pub async fn use_recovery_code<'r>(
+    pg: Postgres<'r>,
+    userid: Uuid,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let code = code.trim().to_lowercase();
+    // Checking a code against the hashes is expensive, so don't bother with anything that can't
+    // be one.
+    if code.len() != encode_secret(&[0; RECOVERY_CODE_LEN]).len() {
+        return Ok(false);
+    }
+    let candidates = sqlx::query!(
+        r#"
+        SELECT
+            code_id, code_hash
+        FROM
+            public.totp_recovery_codes
+        WHERE
+            userid = $1 AND used_at IS NULL"#,
+        userid,
+    )
+    .fetch_all(pg)
+    .await?;
+
+    for candidate in candidates {
+        if verify_password(code.as_bytes(), &candidate.code_hash).unwrap_or(false) {
+            let marked = sqlx::query!(
+                r#"
+                UPDATE public.totp_recovery_codes
+                SET used_at = now()
+                WHERE code_id = $1 AND used_at IS NULL"#,
+                candidate.code_id,
+            )
+            .execute(pg)
+            .await?;
+            return Ok(marked.rows_affected() == 1);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The shared secret of the test vectors in RFC 4226 and RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_rfc4226_test_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, expected) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *expected, "counter {}", counter);
+        }
+    }
+
+    /// RFC 6238 appendix B lists 8 digit codes, of which a 6 digit code is the last 6 digits.
+    #[test]
+    fn totp_rfc6238_test_vectors() {
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+        for (unix_time, code) in vectors.iter() {
+            let code = &code[2..];
+            assert_eq!(
+                verify_code_at(SECRET, code, *unix_time),
+                Some(unix_time / PERIOD_SECONDS),
+                "time {}",
+                unix_time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_allowed_drift() {
+        let unix_time = 1111111111;
+        let step = unix_time / PERIOD_SECONDS;
+        for drift in &[-1, 1] {
+            let code_step = (step as i64 + drift) as u64;
+            let code = format!("{:06}", hotp(SECRET, code_step));
+            assert_eq!(verify_code_at(SECRET, &code, unix_time), Some(code_step));
+        }
+        for drift in &[-2, 2] {
+            let code = format!("{:06}", hotp(SECRET, (step as i64 + drift) as u64));
+            assert_eq!(verify_code_at(SECRET, &code, unix_time), None);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(verify_code_at(SECRET, " 287082 ", 59), Some(1));
+        assert_eq!(verify_code_at(SECRET, "287083", 59), None);
+        assert_eq!(verify_code_at(SECRET, "94287082", 59), None);
+        assert_eq!(verify_code_at(SECRET, "28708", 59), None);
+        assert_eq!(verify_code_at(SECRET, "+87082", 59), None);
+        assert_eq!(verify_code_at(SECRET, "", 59), None);
+    }
+}
@@ -18,11 +18,14 @@ impl Auth {
 impl FromStr for Auth {
     type Err = AuthParseError;
 
+    /// Auth schemes are case-insensitive, see RFC 7235 section 2.1.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Basic" => Ok(Self::Basic),
-            "Digest" => Ok(Self::Digest),
-            _ => Err(AuthParseError { _priv: () }),
+        if s.eq_ignore_ascii_case("Basic") {
+            Ok(Self::Basic)
+        } else if s.eq_ignore_ascii_case("Digest") {
+            Ok(Self::Digest)
+        } else {
+            Err(AuthParseError { _priv: () })
         }
     }
 }
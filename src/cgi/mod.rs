@@ -1,56 +1,164 @@
 use std::{
-    collections::HashMap,
+    env,
     ffi::OsStr,
     fmt,
-    io::{self, Read},
-    process::{Command, Stdio},
+    io::{self, Cursor},
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::{error, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    process::{Child, ChildStderr, ChildStdout, Command},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
 };
 
 pub mod auth;
+mod parse;
 pub mod rocket;
 
 use auth::Auth;
+pub use parse::{CgiHeader, ParseCgiOutputError};
+
+/// Upper bound on the size of the header a script may write before its body.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+/// How long a script may run by default, including the time it takes to send its output.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// How many scripts may run at the same time by default.
+const DEFAULT_MAX_PROCESSES: usize = 32;
+/// How long a script waits for one of the others to finish before the request is turned away.
+const MAX_QUEUE_TIME: Duration = Duration::from_secs(30);
+
+/// Variables of the server's environment that are passed on to scripts. Anything else a
+/// script needs has to be set explicitly.
+const INHERITED_ENV_VARS: &[&str] = &["PATH"];
+
+/// Request headers that are not passed on as `HTTP_*` meta-variables, either because they are
+/// already available as CONTENT_LENGTH and CONTENT_TYPE, because they carry credentials, or
+/// because HTTP_PROXY would be mistaken for the proxy setting by the script (httpoxy).
+const EXCLUDED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-length",
+    "content-type",
+    "proxy",
+    "proxy-authorization",
+];
+
+/// Limits on the processes started for scripts. Clones share the cap on the number of
+/// processes running at the same time.
+#[derive(Clone, Debug)]
+pub struct CgiLimits {
+    timeout: Option<Duration>,
+    /// Seconds of CPU time, enforced through RLIMIT_CPU. Unset by default, as it counts the
+    /// threads of `git pack-objects` together, which can add up quickly for a large clone.
+    cpu_time: Option<u64>,
+    /// Bytes of virtual memory, enforced through RLIMIT_AS. Unset by default, as it counts the
+    /// packfiles git maps into memory as well.
+    address_space: Option<u64>,
+    processes: Arc<Semaphore>,
+}
+
+impl CgiLimits {
+    pub fn new(max_processes: usize) -> Self {
+        Self {
+            timeout: Some(DEFAULT_TIMEOUT),
+            cpu_time: None,
+            address_space: None,
+            processes: Arc::new(Semaphore::new(max_processes)),
+        }
+    }
+
+    /// The wall-clock time after which a script is killed, or `None` to let it run for as
+    /// long as it likes.
+    pub fn timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Seconds of CPU time after which a script is killed, or `None` for no limit.
+    pub fn cpu_time(self, cpu_time: Option<u64>) -> Self {
+        Self { cpu_time, ..self }
+    }
+
+    /// Bytes of virtual memory a script may map, or `None` for no limit.
+    pub fn address_space(self, address_space: Option<u64>) -> Self {
+        Self {
+            address_space,
+            ..self
+        }
+    }
+}
 
-// TODO: Use the typestate pattern to make it impossible to invoke a CGI
-//       script with missing required environment variables.
+impl Default for CgiLimits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PROCESSES)
+    }
+}
+
+/// Marks a required meta-variable of a `CgiScript` that hasn't been set yet.
+#[derive(Clone, Copy, Debug)]
+pub struct Missing;
+
+/// An invocation of a CGI/1.1 script as specified in RFC 3875.
+///
+/// The meta-variables a server must always provide are tracked by the type parameters, which
+/// start out as `Missing` and become `&str` once they are set. Only a script with all of them
+/// set can be run.
 #[derive(Debug)]
-pub struct CgiScript<'a> {
+pub struct CgiScript<
+    'a,
+    Method = Missing,
+    Name = Missing,
+    Port = Missing,
+    Addr = Missing,
+    Software = Missing,
+> {
+    vars: MetaVariables<'a>,
+    request_method: Method,
+    server_name: Name,
+    server_port: Port,
+    remote_addr: Addr,
+    server_software: Software,
+}
+
+/// How to invoke a script and the meta-variables that may be left unset.
+#[derive(Debug)]
+struct MetaVariables<'a> {
     command: &'a str,
     args: &'a [&'a str],
     env_vars: &'a [(&'a str, &'a str)],
-    server_software: Option<&'a str>,
-    server_name: Option<&'a str>,
-    server_port: Option<&'a str>,
-    request_method: Option<&'a str>,
-    query_string: Option<&'a str>,
-    remote_host: Option<&'a str>,
-    remote_addr: Option<&'a str>,
+    /// AUTH_TYPE and REMOTE_USER, which are only meaningful together.
+    auth: Option<(Auth, &'a str)>,
+    content_length: Option<u64>,
+    content_type: Option<&'a str>,
     path_info: Option<&'a str>,
     path_translated: Option<&'a str>,
-    auth_type: Option<Auth>,
-    remote_user: Option<&'a str>,
+    query_string: Option<&'a str>,
+    remote_host: Option<&'a str>,
     remote_ident: Option<&'a str>,
-    content_type: Option<&'a str>,
+    script_name: Option<&'a str>,
+    http_headers: Vec<(&'a str, &'a str)>,
+    limits: Option<&'a CgiLimits>,
 }
 
 macro_rules! builder_property {
     ($property:ident, $ty:ty) => {
         #[allow(dead_code)]
-        pub fn $property(self, $property: $ty) -> Self {
-            Self {
-                $property: Some($property),
-                ..self
-            }
+        pub fn $property(mut self, $property: $ty) -> Self {
+            self.vars.$property = Some($property);
+            self
         }
     };
     ($property:ident, $ty:ty, $doc_str:expr) => {
         #[allow(dead_code)]
         #[doc = $doc_str]
-        pub fn $property(self, $property: $ty) -> Self {
-            Self {
-                $property: Some($property),
-                ..self
-            }
+        pub fn $property(mut self, $property: $ty) -> Self {
+            self.vars.$property = Some($property);
+            self
         }
     };
 }
@@ -58,93 +166,372 @@ macro_rules! builder_property {
 impl<'a> CgiScript<'a> {
     pub fn new(command: &'a str, args: &'a [&'a str], env_vars: &'a [(&'a str, &'a str)]) -> Self {
         Self {
-            command,
-            args,
-            env_vars,
-            server_software: None,
-            server_name: None,
-            server_port: None,
-            request_method: None,
-            query_string: None,
-            remote_host: None,
-            remote_addr: None,
-            path_info: None,
-            path_translated: None,
-            auth_type: None,
-            remote_user: None,
-            remote_ident: None,
-            content_type: None,
+            vars: MetaVariables {
+                command,
+                args,
+                env_vars,
+                auth: None,
+                content_length: None,
+                content_type: None,
+                path_info: None,
+                path_translated: None,
+                query_string: None,
+                remote_host: None,
+                remote_ident: None,
+                script_name: None,
+                http_headers: Vec::new(),
+                limits: None,
+            },
+            request_method: Missing,
+            server_name: Missing,
+            server_port: Missing,
+            remote_addr: Missing,
+            server_software: Missing,
+        }
+    }
+}
+
+impl<'a, N, P, A, S> CgiScript<'a, Missing, N, P, A, S> {
+    pub fn request_method(self, request_method: &'a str) -> CgiScript<'a, &'a str, N, P, A, S> {
+        CgiScript {
+            vars: self.vars,
+            request_method,
+            server_name: self.server_name,
+            server_port: self.server_port,
+            remote_addr: self.remote_addr,
+            server_software: self.server_software,
+        }
+    }
+}
+
+impl<'a, M, P, A, S> CgiScript<'a, M, Missing, P, A, S> {
+    pub fn server_name(self, server_name: &'a str) -> CgiScript<'a, M, &'a str, P, A, S> {
+        CgiScript {
+            vars: self.vars,
+            request_method: self.request_method,
+            server_name,
+            server_port: self.server_port,
+            remote_addr: self.remote_addr,
+            server_software: self.server_software,
+        }
+    }
+}
+
+impl<'a, M, N, A, S> CgiScript<'a, M, N, Missing, A, S> {
+    pub fn server_port(self, server_port: &'a str) -> CgiScript<'a, M, N, &'a str, A, S> {
+        CgiScript {
+            vars: self.vars,
+            request_method: self.request_method,
+            server_name: self.server_name,
+            server_port,
+            remote_addr: self.remote_addr,
+            server_software: self.server_software,
+        }
+    }
+}
+
+impl<'a, M, N, P, S> CgiScript<'a, M, N, P, Missing, S> {
+    pub fn remote_addr(self, remote_addr: &'a str) -> CgiScript<'a, M, N, P, &'a str, S> {
+        CgiScript {
+            vars: self.vars,
+            request_method: self.request_method,
+            server_name: self.server_name,
+            server_port: self.server_port,
+            remote_addr,
+            server_software: self.server_software,
         }
     }
+}
+
+impl<'a, M, N, P, A> CgiScript<'a, M, N, P, A, Missing> {
+    pub fn server_software(self, server_software: &'a str) -> CgiScript<'a, M, N, P, A, &'a str> {
+        CgiScript {
+            vars: self.vars,
+            request_method: self.request_method,
+            server_name: self.server_name,
+            server_port: self.server_port,
+            remote_addr: self.remote_addr,
+            server_software,
+        }
+    }
+}
 
-    builder_property!(auth_type, Auth);
+impl<'a, M, N, P, A, S> CgiScript<'a, M, N, P, A, S> {
+    builder_property!(content_length, u64);
     builder_property!(content_type, &'a str);
     builder_property!(path_info, &'a str, "PATH_INFO should not be URL-encoded.");
     builder_property!(path_translated, &'a str);
     builder_property!(query_string, &'a str);
-    builder_property!(remote_addr, &'a str);
     builder_property!(remote_host, &'a str);
     builder_property!(remote_ident, &'a str);
-    builder_property!(remote_user, &'a str);
-    builder_property!(request_method, &'a str);
-    builder_property!(server_name, &'a str);
-    builder_property!(server_port, &'a str);
-    builder_property!(server_software, &'a str);
-
-    pub fn run<R: Read>(self, mut data: R) -> Result<CgiResponse, CgiScriptError> {
-        let mut cmd = Command::new(&self.command);
-        cmd.args(self.args).envs(
-            self.env_vars
+    builder_property!(limits, &'a CgiLimits);
+    builder_property!(
+        script_name,
+        &'a str,
+        "The path the script is mounted at, without a trailing '/'."
+    );
+
+    /// Sets AUTH_TYPE and REMOTE_USER for a request the server has authenticated.
+    pub fn remote_user(mut self, auth_type: Auth, remote_user: &'a str) -> Self {
+        self.vars.auth = Some((auth_type, remote_user));
+        self
+    }
+
+    /// Passes a request header on to the script as an `HTTP_*` meta-variable.
+    pub fn http_header(mut self, name: &'a str, value: &'a str) -> Self {
+        self.vars.http_headers.push((name, value));
+        self
+    }
+}
+
+impl<'a> CgiScript<'a, &'a str, &'a str, &'a str, &'a str, &'a str> {
+    /// Starts the script and waits until it has written its header. `data` is piped into its
+    /// standard input in the background, and the rest of its standard output is the body of
+    /// the returned response, so neither is ever held in memory as a whole.
+    ///
+    /// Anything the script writes to its standard error is logged, along with the request it
+    /// is running for.
+    pub async fn run<R>(self, mut data: R) -> Result<CgiResponse, CgiScriptError>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let vars = &self.vars;
+        let mut cmd = Command::new(vars.command);
+        // Nothing of the server's own environment reaches the script unless it is listed.
+        cmd.env_clear();
+        for name in INHERITED_ENV_VARS {
+            if let Some(value) = env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+        cmd.args(vars.args).envs(
+            vars.env_vars
                 .iter()
                 .map(|(key, value)| (key.to_string(), value.to_string())),
         );
-
-        // FIXME: Add more CGI environment variables and ensure the current ones are correct.
+        for (name, value) in http_meta_variables(&vars.http_headers) {
+            cmd.env(name, value);
+        }
 
         opt_env(
             &mut cmd,
             "AUTH_TYPE",
-            self.auth_type.map(|auth| auth.as_str()),
+            vars.auth.map(|(auth_type, _)| auth_type.as_str()),
+        );
+        opt_env(
+            &mut cmd,
+            "CONTENT_LENGTH",
+            vars.content_length
+                .map(|content_length| content_length.to_string()),
         );
-        // TODO: Add CONTENT_LENGTH
-        opt_env(&mut cmd, "CONTENT_TYPE", self.content_type);
+        opt_env(&mut cmd, "CONTENT_TYPE", vars.content_type);
         cmd.env("GATEWAY_INTERFACE", "CGI/1.1");
-        // FIXME: Make sure that paths behave properly when a CgiScript route is mounted
-        //        somewhere other than at the root path.
-        // FIXME: Handle paths that end in .git
-        cmd.env("PATH_INFO", self.path_info.unwrap_or("/"));
-        // FIXME: Make sure this does the correct thing.
-        opt_env(&mut cmd, "PATH_TRANSLATED", self.path_translated);
-        cmd.env("QUERY_STRING", self.query_string.unwrap_or(""));
-        // TODO: Error when not set
-        opt_env(&mut cmd, "REMOTE_ADDR", self.remote_addr);
-        opt_env(&mut cmd, "REMOTE_HOST", self.remote_host);
-        opt_env(&mut cmd, "REMOTE_IDENT", self.remote_ident);
-        // TODO: Error when AUTH_TYPE is set but REMOTE_USER isn't
-        opt_env(&mut cmd, "REMOTE_USER", self.remote_user);
-        // TODO: Error when REQUEST_METHOD is not set
-        opt_env(&mut cmd, "REQUEST_METHOD", self.request_method);
-        // TODO: Add SCRIPT_NAME
-        // TODO: Error when SERVER_NAME is not set
-        opt_env(&mut cmd, "SERVER_NAME", self.server_name);
-        // TODO: Error when SERVER_PORT is not set
-        opt_env(&mut cmd, "SERVER_PORT", self.server_port);
+        opt_env(&mut cmd, "PATH_INFO", vars.path_info);
+        opt_env(&mut cmd, "PATH_TRANSLATED", vars.path_translated);
+        cmd.env("QUERY_STRING", vars.query_string.unwrap_or(""));
+        cmd.env("REMOTE_ADDR", self.remote_addr);
+        // Servers that don't look up the host name of the client substitute its address.
+        cmd.env("REMOTE_HOST", vars.remote_host.unwrap_or(self.remote_addr));
+        opt_env(&mut cmd, "REMOTE_IDENT", vars.remote_ident);
+        opt_env(
+            &mut cmd,
+            "REMOTE_USER",
+            vars.auth.map(|(_, remote_user)| remote_user),
+        );
+        cmd.env("REQUEST_METHOD", self.request_method);
+        cmd.env("SCRIPT_NAME", vars.script_name.unwrap_or(""));
+        cmd.env("SERVER_NAME", self.server_name);
+        cmd.env("SERVER_PORT", self.server_port);
         // TOOD: Evalute if it's useful to forward HTTP/2 requests through CGI
         // TODO: Support setting SERVER_PROTOCOL to "INCLUDED"
         cmd.env("SERVER_PROTOCOL", "HTTP/1.1");
-        // TODO: Error when not set
-        opt_env(&mut cmd, "SERVER_SOFTWARE", self.server_software);
+        cmd.env("SERVER_SOFTWARE", self.server_software);
 
+        let limits = vars.limits;
+        #[cfg(unix)]
+        if let Some(limits) = limits {
+            set_rlimits(&mut cmd, limits.cpu_time, limits.address_space);
+        }
         cmd.stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            // Stops the script if the client goes away before it has written its header.
+            .kill_on_drop(true);
+
+        let context = format!(
+            "CGI script {} ({} {}{} for {})",
+            vars.command,
+            self.request_method,
+            vars.script_name.unwrap_or(""),
+            vars.path_info.unwrap_or(""),
+            self.remote_addr
+        );
+        let timeout = limits.and_then(|limits| limits.timeout);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let permit = match limits {
+            Some(limits) => Some(
+                time::timeout(MAX_QUEUE_TIME, limits.processes.clone().acquire_owned())
+                    .await
+                    .map_err(|_| CgiScriptError::Busy)?
+                    .map_err(|_| CgiScriptError::Busy)?,
+            ),
+            None => None,
+        };
+
+        let mut child = cmd.spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        tokio::spawn(log_stderr(stderr, context.clone()));
+
+        // The script may start writing its output before it has read all of its input, so the
+        // two have to be pumped concurrently.
+        let stdin_context = context.clone();
+        tokio::spawn(async move {
+            if let Err(err) = tokio::io::copy(&mut data, &mut stdin).await {
+                warn!("{}: Could not pass on request body: {}", stdin_context, err);
+            }
+        });
+
+        let (header, body) = match deadline {
+            Some(deadline) => time::timeout_at(deadline, read_header(&mut stdout))
+                .await
+                .map_err(|_| CgiScriptError::Timeout)??,
+            None => read_header(&mut stdout).await?,
+        };
+        if header.status_code >= 500 {
+            warn!(
+                "{}: Responded with {} {}",
+                context,
+                header.status_code,
+                header.reason.as_deref().unwrap_or_default()
+            );
+        }
+        tokio::spawn(supervise(child, permit, deadline, context));
+
+        Ok(CgiResponse {
+            header,
+            body: CgiBody {
+                buffered: Cursor::new(body),
+                stdout,
+            },
+        })
+    }
+}
+
+/// Reads the header of a script's output, and whatever part of the body came with it.
+async fn read_header(stdout: &mut ChildStdout) -> Result<(CgiHeader, Vec<u8>), CgiScriptError> {
+    let mut output = Vec::new();
+    loop {
+        if let Some((header, body_start)) = parse::parse_cgi_output(&output)? {
+            return Ok((header, output.split_off(body_start)));
+        }
+        if output.len() > MAX_HEADER_SIZE {
+            return Err(ParseCgiOutputError::HeaderTooLarge.into());
+        }
+        if stdout.read_buf(&mut output).await? == 0 {
+            return Err(ParseCgiOutputError::NoEndOfHeader.into());
+        }
+    }
+}
+
+/// Waits for a script to exit while its body is being sent, and kills it once `deadline` has
+/// passed. The script counts towards the cap on running processes until then.
+async fn supervise(
+    mut child: Child,
+    _permit: Option<OwnedSemaphorePermit>,
+    deadline: Option<Instant>,
+    context: String,
+) {
+    let status = match deadline {
+        Some(deadline) => match time::timeout_at(deadline, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                warn!("{}: Killed because it ran for too long", context);
+                if let Err(err) = child.kill().await {
+                    error!("{}: Could not kill: {}", context, err);
+                }
+                return;
+            }
+        },
+        None => child.wait().await,
+    };
+    match status {
+        Ok(status) if !status.success() => warn!("{}: Exited with {}", context, status),
+        Ok(_) => {}
+        Err(err) => error!("{}: Could not wait for exit: {}", context, err),
+    }
+}
+
+async fn log_stderr(stderr: ChildStderr, context: String) {
+    let mut stderr = BufReader::new(stderr);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match stderr.read_until(b'\n', &mut line).await {
+            Ok(0) => break,
+            Ok(_) => warn!("{}: {}", context, String::from_utf8_lossy(&line).trim_end()),
+            Err(err) => {
+                warn!("{}: Could not read standard error: {}", context, err);
+                break;
+            }
+        }
+    }
+}
 
-        let mut process = cmd.spawn()?;
-        io::copy(&mut data, &mut process.stdin.take().unwrap())?;
-        let output = dbg!(process.wait_with_output()?);
+/// Applies the resource limits to the process before the script is executed.
+#[cfg(unix)]
+fn set_rlimits(cmd: &mut Command, cpu_time: Option<u64>, address_space: Option<u64>) {
+    if cpu_time.is_none() && address_space.is_none() {
+        return;
+    }
+    let rlimit = |limit: u64| libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    // Safety: setrlimit is async-signal-safe, and nothing is allocated between fork and exec.
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(cpu_time) = cpu_time {
+                if libc::setrlimit(libc::RLIMIT_CPU, &rlimit(cpu_time)) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(address_space) = address_space {
+                if libc::setrlimit(libc::RLIMIT_AS, &rlimit(address_space)) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
 
-        Ok(parse_cgi_output(&output.stdout)?)
+/// Turns request headers into `HTTP_*` meta-variables. Repeated headers are combined into one
+/// value, as HTTP allows.
+fn http_meta_variables(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for (name, value) in headers {
+        if EXCLUDED_HEADERS
+            .iter()
+            .any(|excluded| name.eq_ignore_ascii_case(excluded))
+        {
+            continue;
+        }
+        // '_' is left out as well, so that no two header names map to the same variable.
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            continue;
+        }
+        let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match vars.iter_mut().find(|(existing, _)| *existing == var) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => vars.push((var, value.to_string())),
+        }
     }
+    vars
 }
 
 fn opt_env<K, V>(cmd: &mut Command, key: K, val: Option<V>)
@@ -161,6 +548,10 @@ where
 pub enum CgiScriptError {
     Io(io::Error),
     ParseOutput(ParseCgiOutputError),
+    /// The script didn't write its header in time and was killed.
+    Timeout,
+    /// Too many scripts were already running.
+    Busy,
 }
 
 impl fmt::Display for CgiScriptError {
@@ -168,6 +559,8 @@ impl fmt::Display for CgiScriptError {
         match self {
             Self::Io(err) => write!(f, "I/O error while running CGI script: {}", err),
             Self::ParseOutput(err) => write!(f, "Error while parsing CGI script output: {}", err),
+            Self::Timeout => write!(f, "CGI script timed out"),
+            Self::Busy => write!(f, "Too many CGI scripts are running"),
         }
     }
 }
@@ -186,99 +579,31 @@ impl From<ParseCgiOutputError> for CgiScriptError {
     }
 }
 
-fn parse_cgi_output<'r>(output: &[u8]) -> Result<CgiResponse, ParseCgiOutputError> {
-    let header_end_idx = output
-        .windows(4)
-        .position(|bytes| bytes == b"\r\n\r\n")
-        .ok_or(ParseCgiOutputError::NoEndOfHeader)?;
-    let (raw_header, raw_body) = output.split_at(header_end_idx + 1);
-    // TODO: Determine if it's only git that wants a leading '\n' in the repsonse body or if this is the standard.
-    let raw_body = &raw_body[3..];
-
-    // Copied from https://github.com/tomaka/rouille/blob/master/src/cgi.rs#L142-L158
-    // with some modifications.
-    let mut headers_vec = Vec::new();
-    let mut status_code = 200;
-    for header in raw_header.split(|byte| *byte == b'\n') {
-        if header.is_empty() {
-            break;
-        }
-
-        let (raw_name, raw_value) = header.split_at(
-            header
-                .iter()
-                .position(|byte| *byte == b':')
-                .ok_or(ParseCgiOutputError::NoHeaderValue)?,
-        );
-        let last_value_idx = {
-            if raw_value.last() == Some(&b'\r') {
-                raw_value.len() - 1
-            } else {
-                raw_value.len()
-            }
-        };
-        let raw_value = &raw_value[2..last_value_idx];
-
-        if raw_name == b"Status" {
-            status_code = std::str::from_utf8(&raw_value[0..3])
-                .map_err(|_| ParseCgiOutputError::InvalidUtf8InStatus)?
-                .parse()
-                .map_err(|_| ParseCgiOutputError::InvalidStatus)?;
-        } else {
-            headers_vec.push((raw_name, raw_value));
-        }
-    }
-    // End of copied section.
-
-    let mut headers = HashMap::with_capacity(headers_vec.len());
-    for (raw_header_name, raw_header_value) in headers_vec {
-        headers.insert(
-            String::from_utf8(raw_header_name.to_vec())
-                .map_err(|_| ParseCgiOutputError::InvalidUtf8InHeaderName)?,
-            String::from_utf8(raw_header_value.to_vec())
-                .map_err(|_| ParseCgiOutputError::InvalidUtf8InHeaderValue)?,
-        );
-    }
-    let body = raw_body.to_vec();
-
-    Ok(CgiResponse {
-        status_code,
-        headers,
-        body,
-    })
+#[derive(Debug)]
+pub struct CgiResponse {
+    header: CgiHeader,
+    body: CgiBody,
 }
 
+/// The body of a script's output, read as the script writes it.
 #[derive(Debug)]
-pub enum ParseCgiOutputError {
-    NoEndOfHeader,
-    NoHeaderValue,
-    InvalidUtf8InStatus,
-    InvalidStatus,
-    InvalidUtf8InHeaderName,
-    InvalidUtf8InHeaderValue,
+pub struct CgiBody {
+    /// What was read together with the header.
+    buffered: Cursor<Vec<u8>>,
+    stdout: ChildStdout,
 }
 
-impl fmt::Display for ParseCgiOutputError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Self::NoEndOfHeader => write!(
-                f,
-                r"Could not find end of header: No '\r\n\r\n' sequence was found"
-            ),
-            Self::NoHeaderValue => write!(f, "Could not find header value: No ': ' delimiter"),
-            Self::InvalidUtf8InStatus => write!(f, "Status code contains invalid UTF-8"),
-            Self::InvalidStatus => write!(f, "Invalid status code"),
-            Self::InvalidUtf8InHeaderName => write!(f, "Invalid UTF-8 in header name"),
-            Self::InvalidUtf8InHeaderValue => write!(f, "Invalid UTF-8 in header value"),
+impl AsyncRead for CgiBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if (this.buffered.position() as usize) < this.buffered.get_ref().len() {
+            Pin::new(&mut this.buffered).poll_read(cx, buf)
+        } else {
+            Pin::new(&mut this.stdout).poll_read(cx, buf)
         }
     }
 }
-
-impl std::error::Error for ParseCgiOutputError {}
-
-#[derive(Debug)]
-pub struct CgiResponse {
-    status_code: u16,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
-}
@@ -0,0 +1,422 @@
+use std::{fmt, str};
+
+use super::MAX_HEADER_SIZE;
+
+/// The header of a script's output, as described in section 6 of RFC 3875.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CgiHeader {
+    pub status_code: u16,
+    /// The reason phrase of the Status header or of the status line of an NPH script.
+    pub reason: Option<String>,
+    /// The header fields to send to the client, in order and including repeated ones.
+    pub headers: Vec<(String, String)>,
+}
+
+/// Parses the header at the start of a script's output.
+///
+/// Returns `None` if `output` doesn't contain the whole header yet, and otherwise the header
+/// and the index the body starts at. The header ends at the first empty line, and lines may
+/// end in either "\r\n" or "\n". Output starting with an HTTP status line is taken to be from
+/// a non-parsed header (NPH) script.
+pub fn parse_cgi_output(output: &[u8]) -> Result<Option<(CgiHeader, usize)>, ParseCgiOutputError> {
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let body_start = loop {
+        let line_end = match output[line_start..].iter().position(|byte| *byte == b'\n') {
+            Some(idx) => line_start + idx,
+            None => return Ok(None),
+        };
+        let mut line = &output[line_start..line_end];
+        if line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+        line_start = line_end + 1;
+        if line.is_empty() {
+            break line_start;
+        }
+        lines.push(line);
+    };
+
+    let mut lines = lines.into_iter().peekable();
+    let mut status = match lines.peek() {
+        Some(line) if line.starts_with(b"HTTP/") => {
+            let line = lines.next().expect("the line was peeked at");
+            let status = line
+                .iter()
+                .position(|byte| *byte == b' ')
+                .map(|idx| &line[idx + 1..])
+                .ok_or(ParseCgiOutputError::InvalidStatus)?;
+            Some(parse_status(status)?)
+        }
+        Some(_) => None,
+        None => return Err(ParseCgiOutputError::NoHeaders),
+    };
+
+    let mut location = false;
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines {
+        // A line starting with whitespace continues the value of the previous field.
+        if line[0] == b' ' || line[0] == b'\t' {
+            let (_, value) = headers
+                .last_mut()
+                .ok_or(ParseCgiOutputError::UnexpectedContinuation)?;
+            let continuation = field_value(line)?;
+            if !continuation.is_empty() {
+                value.push(' ');
+                value.push_str(continuation);
+            }
+            continue;
+        }
+
+        let colon = line
+            .iter()
+            .position(|byte| *byte == b':')
+            .ok_or(ParseCgiOutputError::NoHeaderValue)?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if name.is_empty() || !name.iter().all(|byte| is_token_char(*byte)) {
+            return Err(ParseCgiOutputError::InvalidHeaderName);
+        }
+        // Only ASCII is left.
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        if name.eq_ignore_ascii_case("Status") {
+            status = Some(parse_status(value)?);
+            continue;
+        }
+        if name.eq_ignore_ascii_case("Location") {
+            location = true;
+        }
+        headers.push((name, field_value(value)?.to_string()));
+    }
+
+    // A Location without a Status is a redirect. Local redirects, to a path on this server,
+    // are meant to be followed by the server itself, but letting the client follow them ends
+    // up at the same response.
+    let (status_code, reason) = match status {
+        Some(status) => status,
+        None if location => (302, Some("Found".to_string())),
+        None => (200, None),
+    };
+    let header = CgiHeader {
+        status_code,
+        reason,
+        headers,
+    };
+    Ok(Some((header, body_start)))
+}
+
+/// Parses `<status-code> [<reason-phrase>]`.
+fn parse_status(raw_status: &[u8]) -> Result<(u16, Option<String>), ParseCgiOutputError> {
+    let status = str::from_utf8(raw_status)
+        .map_err(|_| ParseCgiOutputError::InvalidUtf8InStatus)?
+        .trim_matches(|c| c == ' ' || c == '\t');
+    let (code, reason) = match status.find(' ') {
+        Some(idx) => (&status[..idx], status[idx + 1..].trim()),
+        None => (status, ""),
+    };
+    if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ParseCgiOutputError::InvalidStatus);
+    }
+    let code = code
+        .parse()
+        .map_err(|_| ParseCgiOutputError::InvalidStatus)?;
+    if !(100..=599).contains(&code) {
+        return Err(ParseCgiOutputError::InvalidStatus);
+    }
+    let reason = if reason.is_empty() {
+        None
+    } else {
+        Some(reason.to_string())
+    };
+    Ok((code, reason))
+}
+
+/// The value of a header field without the whitespace around it.
+fn field_value(raw_value: &[u8]) -> Result<&str, ParseCgiOutputError> {
+    str::from_utf8(raw_value)
+        .map(|value| value.trim_matches(|c| c == ' ' || c == '\t'))
+        .map_err(|_| ParseCgiOutputError::InvalidUtf8InHeaderValue)
+}
+
+/// Whether `byte` may appear in a header field name, as defined by RFC 7230.
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+#[derive(Debug)]
+pub enum ParseCgiOutputError {
+    NoEndOfHeader,
+    HeaderTooLarge,
+    NoHeaders,
+    NoHeaderValue,
+    InvalidHeaderName,
+    UnexpectedContinuation,
+    InvalidUtf8InStatus,
+    InvalidStatus,
+    InvalidUtf8InHeaderValue,
+}
+
+impl fmt::Display for ParseCgiOutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoEndOfHeader => write!(
+                f,
+                "Could not find end of header: Output ended before an empty line"
+            ),
+            Self::HeaderTooLarge => write!(
+                f,
+                "Header is larger than the maximum of {} bytes",
+                MAX_HEADER_SIZE
+            ),
+            Self::NoHeaders => write!(f, "Output contains no header fields"),
+            Self::NoHeaderValue => write!(f, "Could not find header value: No ':' delimiter"),
+            Self::InvalidHeaderName => write!(f, "Invalid character in header name"),
+            Self::UnexpectedContinuation => {
+                write!(f, "Continuation line without a preceding header field")
+            }
+            Self::InvalidUtf8InStatus => write!(f, "Status code contains invalid UTF-8"),
+            Self::InvalidStatus => write!(f, "Invalid status code"),
+            Self::InvalidUtf8InHeaderValue => write!(f, "Invalid UTF-8 in header value"),
+        }
+    }
+}
+
+impl std::error::Error for ParseCgiOutputError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `output` and splits off the body.
+    fn parse(output: &[u8]) -> (CgiHeader, &[u8]) {
+        let (header, body_start) = parse_cgi_output(output)
+            .expect("output should parse")
+            .expect("header should be complete");
+        (header, &output[body_start..])
+    }
+
+    fn values<'a>(header: &'a CgiHeader, name: &str) -> Vec<&'a str> {
+        header
+            .headers
+            .iter()
+            .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    const NO_CACHE_HEADERS: &[(&str, &str)] = &[
+        ("Expires", "Fri, 01 Jan 1980 00:00:00 GMT"),
+        ("Pragma", "no-cache"),
+        ("Cache-Control", "no-cache, max-age=0, must-revalidate"),
+    ];
+
+    #[test]
+    fn git_smart_ref_advertisement() {
+        let output = include_bytes!("testdata/git_http_backend/info_refs_upload_pack.out");
+        let (header, body) = parse(output);
+        assert_eq!(header.status_code, 200);
+        assert_eq!(header.reason, None);
+        let mut expected: Vec<_> = NO_CACHE_HEADERS.to_vec();
+        expected.push((
+            "Content-Type",
+            "application/x-git-upload-pack-advertisement",
+        ));
+        assert_eq!(
+            header
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert!(body.starts_with(b"001e# service=git-upload-pack\n0000"));
+        assert!(body.ends_with(b"0000"));
+    }
+
+    #[test]
+    fn git_dumb_ref_advertisement() {
+        let output = include_bytes!("testdata/git_http_backend/info_refs_dumb.out");
+        let (header, body) = parse(output);
+        assert_eq!(header.status_code, 200);
+        assert_eq!(values(&header, "Content-Type"), ["text/plain"]);
+        assert_eq!(values(&header, "Content-Length"), ["57"]);
+        assert_eq!(body.len(), 57);
+        assert!(body.ends_with(b"\trefs/heads/main\n"));
+    }
+
+    #[test]
+    fn git_upload_pack_result() {
+        let output = include_bytes!("testdata/git_http_backend/upload_pack.out");
+        let (header, body) = parse(output);
+        assert_eq!(header.status_code, 200);
+        assert_eq!(
+            values(&header, "Content-Type"),
+            ["application/x-git-upload-pack-result"]
+        );
+        // The body is binary and starts right after the empty line.
+        assert!(body.starts_with(b"0008NAK\nPACK\0\0\0\x02"));
+    }
+
+    #[test]
+    fn git_not_found() {
+        let output = include_bytes!("testdata/git_http_backend/not_found.out");
+        let (header, body) = parse(output);
+        assert_eq!(header.status_code, 404);
+        assert_eq!(header.reason.as_deref(), Some("Not Found"));
+        assert!(values(&header, "Status").is_empty());
+        assert_eq!(header.headers.len(), 3);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn git_forbidden_keeps_repeated_headers() {
+        let output = include_bytes!("testdata/git_http_backend/receive_pack_forbidden.out");
+        let (header, body) = parse(output);
+        assert_eq!(header.status_code, 403);
+        assert_eq!(header.reason.as_deref(), Some("Forbidden"));
+        // git writes the caching headers both before and after the Status.
+        assert_eq!(header.headers.len(), 6);
+        assert_eq!(values(&header, "Pragma"), ["no-cache", "no-cache"]);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn incomplete_header() {
+        let output = include_bytes!("testdata/git_http_backend/info_refs_upload_pack.out");
+        let header_len = output
+            .windows(4)
+            .position(|bytes| bytes == b"\r\n\r\n")
+            .unwrap();
+        for len in 0..header_len + 4 {
+            assert!(parse_cgi_output(&output[..len]).unwrap().is_none());
+        }
+        assert!(parse_cgi_output(&output[..header_len + 4])
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn bare_line_feeds() {
+        let (header, body) = parse(b"Content-Type: text/plain\nX-Foo: bar\n\nbody\r\n\r\n");
+        assert_eq!(header.status_code, 200);
+        assert_eq!(values(&header, "X-Foo"), ["bar"]);
+        assert_eq!(body, b"body\r\n\r\n");
+    }
+
+    #[test]
+    fn mixed_line_endings() {
+        let (header, body) = parse(b"Content-Type: text/plain\r\n\nbody");
+        assert_eq!(values(&header, "Content-Type"), ["text/plain"]);
+        assert_eq!(body, b"body");
+    }
+
+    #[test]
+    fn whitespace_around_values() {
+        let (header, _) = parse(b"Content-Type:text/plain\nX-Foo:  \tbar baz \t\nX-Empty:\n\n");
+        assert_eq!(values(&header, "Content-Type"), ["text/plain"]);
+        assert_eq!(values(&header, "X-Foo"), ["bar baz"]);
+        assert_eq!(values(&header, "X-Empty"), [""]);
+    }
+
+    #[test]
+    fn folded_values() {
+        let (header, _) = parse(b"X-Foo: bar\n  baz\n\tqux\nContent-Type: text/plain\n\n");
+        assert_eq!(values(&header, "X-Foo"), ["bar baz qux"]);
+    }
+
+    #[test]
+    fn status_without_reason() {
+        let (header, _) = parse(b"Status: 204\n\n");
+        assert_eq!(header.status_code, 204);
+        assert_eq!(header.reason, None);
+        assert!(header.headers.is_empty());
+    }
+
+    #[test]
+    fn status_with_custom_reason() {
+        let (header, _) = parse(b"status:418 I'm a teapot  \nContent-Type: text/plain\n\n");
+        assert_eq!(header.status_code, 418);
+        assert_eq!(header.reason.as_deref(), Some("I'm a teapot"));
+    }
+
+    #[test]
+    fn invalid_status() {
+        for output in &[
+            &b"Status: 20\n\n"[..],
+            b"Status: 2000 OK\n\n",
+            b"Status: abc\n\n",
+            b"Status: 099 Too low\n\n",
+            b"Status: 600 Too high\n\n",
+            b"Status:\n\n",
+        ] {
+            assert!(matches!(
+                parse_cgi_output(output),
+                Err(ParseCgiOutputError::InvalidStatus)
+            ));
+        }
+    }
+
+    #[test]
+    fn client_redirect() {
+        let (header, body) = parse(b"Location: https://example.com/\n\n");
+        assert_eq!(header.status_code, 302);
+        assert_eq!(values(&header, "Location"), ["https://example.com/"]);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn local_redirect() {
+        let (header, _) = parse(b"Location: /~alice/hello\r\n\r\n");
+        assert_eq!(header.status_code, 302);
+        assert_eq!(values(&header, "Location"), ["/~alice/hello"]);
+    }
+
+    #[test]
+    fn client_redirect_with_document() {
+        let (header, body) = parse(
+            b"Status: 301 Moved Permanently\nLocation: https://example.com/\n\
+              Content-Type: text/html\n\n<a href=\"https://example.com/\">moved</a>",
+        );
+        assert_eq!(header.status_code, 301);
+        assert_eq!(values(&header, "Location"), ["https://example.com/"]);
+        assert_eq!(body, &b"<a href=\"https://example.com/\">moved</a>"[..]);
+    }
+
+    #[test]
+    fn nph_output() {
+        let (header, body) =
+            parse(b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\nnot found\n");
+        assert_eq!(header.status_code, 404);
+        assert_eq!(header.reason.as_deref(), Some("Not Found"));
+        assert_eq!(values(&header, "Content-Type"), ["text/plain"]);
+        assert_eq!(body, b"not found\n");
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert!(matches!(
+            parse_cgi_output(b"\r\nbody"),
+            Err(ParseCgiOutputError::NoHeaders)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b"Content-Type text/plain\n\n"),
+            Err(ParseCgiOutputError::NoHeaderValue)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b"Content Type: text/plain\n\n"),
+            Err(ParseCgiOutputError::InvalidHeaderName)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b": text/plain\n\n"),
+            Err(ParseCgiOutputError::InvalidHeaderName)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b" folded\nContent-Type: text/plain\n\n"),
+            Err(ParseCgiOutputError::UnexpectedContinuation)
+        ));
+        assert!(matches!(
+            parse_cgi_output(b"X-Foo: \xff\n\n"),
+            Err(ParseCgiOutputError::InvalidUtf8InHeaderValue)
+        ));
+    }
+}
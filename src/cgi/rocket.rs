@@ -1,17 +1,217 @@
-use std::io::Cursor;
+use std::path::{Path, PathBuf};
 
-use rocket::{http::Status, Response};
+use log::error;
+use rocket::{
+    data::ByteUnit,
+    handler::{Handler, Outcome},
+    http::{Method, RawStr, Status},
+    Config, Data, Request, Response, Route, State,
+};
 
-use super::CgiResponse;
+use super::{CgiLimits, CgiResponse, CgiScript, CgiScriptError};
 
 impl<'r> Into<Response<'r>> for CgiResponse {
     fn into(self) -> Response<'r> {
         let mut response = Response::new();
-        response.set_status(Status::raw(self.status_code));
-        for (header_name, header_value) in self.headers {
+        // The reason phrase is left to the HTTP implementation, which uses the standard one.
+        response.set_status(Status::raw(self.header.status_code));
+        for (header_name, header_value) in self.header.headers {
             response.adjoin_raw_header(header_name, header_value);
         }
-        response.set_sized_body(None, Cursor::new(self.body));
+        response.set_streamed_body(self.body);
         response
     }
 }
+
+impl CgiScriptError {
+    /// The status to respond with when a script couldn't produce a response of its own.
+    pub fn status(&self) -> Status {
+        match self {
+            Self::Io(_) | Self::ParseOutput(_) => Status::BadGateway,
+            Self::Timeout => Status::GatewayTimeout,
+            Self::Busy => Status::ServiceUnavailable,
+        }
+    }
+}
+
+/// Turns the result of `CgiScript::run` into the outcome of a handler.
+pub fn outcome<'r>(
+    request: &'r Request<'_>,
+    result: Result<CgiResponse, CgiScriptError>,
+) -> Outcome<'r> {
+    match result {
+        Ok(response) => {
+            let response: Response = response.into();
+            Outcome::from(request, response)
+        }
+        Err(err) => {
+            error!("{} {}: {}", request.method(), request.uri(), err);
+            Outcome::Failure(err.status())
+        }
+    }
+}
+
+/// The host name of a `Host` header value, without the port.
+fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        // An IPv6 address, where the brackets are part of the name.
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
+
+/// The meta-variables that describe a Rocket request, for running a script on its behalf.
+pub struct CgiRequest {
+    request_method: &'static str,
+    query_string: String,
+    server_name: String,
+    server_port: String,
+    remote_addr: String,
+    content_type: Option<String>,
+    content_length: Option<u64>,
+    headers: Vec<(String, String)>,
+}
+
+impl CgiRequest {
+    pub fn new(request: &Request<'_>, config: &Config) -> Self {
+        Self {
+            request_method: request.method().as_str(),
+            query_string: request.uri().query().unwrap_or("").to_string(),
+            server_name: request
+                .headers()
+                .get_one("Host")
+                .map(host_name)
+                .filter(|host| !host.is_empty())
+                .map(str::to_string)
+                .unwrap_or_else(|| config.address.to_string()),
+            server_port: config.port.to_string(),
+            remote_addr: request
+                .client_ip()
+                .map(|ip| ip.to_string())
+                .unwrap_or_default(),
+            content_type: request.content_type().map(|ct| ct.to_string()),
+            content_length: request
+                .headers()
+                .get_one("Content-Length")
+                .and_then(|content_length| content_length.parse().ok()),
+            headers: request
+                .headers()
+                .iter()
+                .map(|header| (header.name().to_string(), header.value().to_string()))
+                .collect(),
+        }
+    }
+
+    /// A script with the meta-variables of the request set. Where the script is mounted and
+    /// the path below that are left to the caller.
+    pub fn script<'a>(
+        &'a self,
+        command: &'a str,
+        args: &'a [&'a str],
+        env_vars: &'a [(&'a str, &'a str)],
+    ) -> CgiScript<'a, &'a str, &'a str, &'a str, &'a str, &'a str> {
+        let mut script = CgiScript::new(command, args, env_vars)
+            .request_method(self.request_method)
+            .server_name(&self.server_name)
+            .server_port(&self.server_port)
+            .remote_addr(&self.remote_addr)
+            .server_software("rocket")
+            .query_string(&self.query_string);
+        if let Some(content_type) = &self.content_type {
+            script = script.content_type(content_type);
+        }
+        if let Some(content_length) = self.content_length {
+            script = script.content_length(content_length);
+        }
+        for (name, value) in &self.headers {
+            script = script.http_header(name, value);
+        }
+        script
+    }
+}
+
+/// Serves a CGI program below the path it is mounted at, e.g. cgit or hgweb.
+///
+/// SCRIPT_NAME is the mount point and PATH_INFO the rest of the requested path, so a script
+/// mounted at `/cgit` sees `/cgit/sourceshack/log` as SCRIPT_NAME `/cgit` and PATH_INFO
+/// `/sourceshack/log`.
+#[derive(Clone, Debug)]
+pub struct CgiHandler {
+    script_path: PathBuf,
+    limits: CgiLimits,
+}
+
+impl CgiHandler {
+    pub fn new<P: AsRef<Path>>(script_path: P) -> Self {
+        Self {
+            script_path: script_path.as_ref().to_path_buf(),
+            limits: CgiLimits::default(),
+        }
+    }
+
+    /// Replaces the default limits on the script's processes.
+    pub fn limits(self, limits: CgiLimits) -> Self {
+        Self { limits, ..self }
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler for CgiHandler {
+    async fn handle<'r, 's: 'r>(&'s self, request: &'r Request<'_>, data: Data) -> Outcome<'r> {
+        let config: State<Config> = match request.guard().await {
+            rocket::outcome::Outcome::Success(config) => config,
+            _ => return Outcome::Failure(Status::InternalServerError),
+        };
+        let (script_name, path_info) = script_name_and_path_info(request);
+        let command = self.script_path.to_string_lossy();
+
+        let cgi_request = CgiRequest::new(request, &config);
+        let mut script = cgi_request
+            .script(&command, &[], &[])
+            .script_name(&script_name)
+            .limits(&self.limits);
+        if let Some(path_info) = &path_info {
+            script = script.path_info(path_info);
+        }
+        outcome(request, script.run(data.open(ByteUnit::max_value())).await)
+    }
+}
+
+/// Splits the path of `request` into the mount point of its route, without a trailing '/',
+/// and the decoded rest of the path, if there is any.
+fn script_name_and_path_info(request: &Request<'_>) -> (String, Option<String>) {
+    let base = request
+        .route()
+        .map(|route| route.base.path().to_string())
+        .unwrap_or_default();
+    let script_name = base.trim_end_matches('/').to_string();
+    let path = request.uri().path().to_string();
+    let rest = path.get(script_name.len()..).unwrap_or_default();
+    let path_info = if rest.is_empty() {
+        None
+    } else {
+        Some(RawStr::from_str(rest).percent_decode_lossy().into_owned())
+    };
+    (script_name, path_info)
+}
+
+impl Into<Vec<Route>> for CgiHandler {
+    fn into(self) -> Vec<Route> {
+        // HEAD requests are answered by the GET route.
+        [
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+            Method::Patch,
+            Method::Options,
+        ]
+        .iter()
+        .map(|method| Route::new(*method, "/<path..>", self.clone()))
+        .collect()
+    }
+}
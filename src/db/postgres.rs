@@ -5,7 +5,7 @@ use rocket::{
     request::{FromRequest, Outcome},
     Request, Rocket,
 };
-use sqlx::{postgres::PgConnectOptions, PgPool};
+use sqlx::{postgres::PgConnectOptions, PgPool, Transaction};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Postgres<'r> {
@@ -16,6 +16,10 @@ impl<'r> Postgres<'r> {
     pub fn fairing() -> PostgresFairing {
         PostgresFairing {}
     }
+
+    pub async fn begin(self) -> Result<Transaction<'static, sqlx::Postgres>, sqlx::Error> {
+        self.pool.begin().await
+    }
 }
 
 #[async_trait::async_trait]
@@ -2,7 +2,8 @@ use std::{ffi::OsStr, fmt, path::Path};
 
 use rocket::{
     http::RawStr,
-    request::{FromFormValue, FromParam},
+    request::{FromFormValue, FromParam, FromRequest, Outcome},
+    Request,
 };
 
 macro_rules! string_wrapper_impls {
@@ -69,6 +70,30 @@ impl<'a> FromParam<'a> for UserNameGuard<'a> {
     }
 }
 
+/// The value of the `Host` header, e.g. for building clone URLs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Host<'a> {
+    host: &'a str,
+}
+
+#[async_trait::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for Host<'a> {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("Host") {
+            Some(host) => Outcome::Success(Self { host }),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Host<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.host.fmt(f)
+    }
+}
+
 /// "ASCCI Alphanumeric + Underscore + Dash"-string
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AaudStr<'a> {
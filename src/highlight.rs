@@ -0,0 +1,68 @@
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use syntect::{
+    easy::HighlightLines,
+    highlighting::ThemeSet,
+    html::{styled_line_to_highlighted_html, IncludeBackground},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::LinesWithEndings,
+};
+
+lazy_static! {
+    static ref SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEME_SET: ThemeSet = ThemeSet::load_defaults();
+}
+
+const THEME: &str = "InspiredGitHub";
+
+/// Highlights `code` with the syntax matching the extension of `file_name` (or failing that,
+/// its first line) and returns one HTML snippet per line, without the line endings.
+pub fn highlight_lines(file_name: &str, code: &str) -> Vec<String> {
+    let syntax = syntax_for(file_name, code);
+    let mut highlighter = HighlightLines::new(syntax, &THEME_SET.themes[THEME]);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let regions: Vec<_> = highlighter
+                .highlight(line, &SYNTAX_SET)
+                .into_iter()
+                .map(|(style, text)| (style, text.trim_end_matches(&['\r', '\n'][..])))
+                .collect();
+            styled_line_to_highlighted_html(&regions, IncludeBackground::No)
+        })
+        .collect()
+}
+
+/// Escapes `code` line by line without highlighting it.
+pub fn plain_lines(code: &str) -> Vec<String> {
+    code.lines().map(escape_html).collect()
+}
+
+fn syntax_for(file_name: &str, code: &str) -> &'static SyntaxReference {
+    Path::new(file_name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| SYNTAX_SET.find_syntax_by_extension(extension))
+        .or_else(|| SYNTAX_SET.find_syntax_by_extension(file_name))
+        .or_else(|| {
+            code.lines()
+                .next()
+                .and_then(|first_line| SYNTAX_SET.find_syntax_by_first_line(first_line))
+        })
+        .unwrap_or_else(|| SYNTAX_SET.find_syntax_plain_text())
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,14 +1,18 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use rocket::Config;
 use rocket_contrib::{serve::StaticFiles, templates::Template};
 
+mod auth;
 mod cgi;
 mod db;
 mod guards;
+mod highlight;
+mod markdown;
 mod routes;
 mod util;
 
+use cgi::{rocket::CgiHandler, CgiLimits};
 use routes::vcs::git::http_backend::GitHttpBackend;
 
 #[tokio::main]
@@ -20,18 +24,66 @@ async fn main() {
     let data_dir = PathBuf::from(util::ensure_correct_path_separator(
         util::read_expected_env_var("SOURCESHACK_DATA_DIR"),
     ));
+    // Read when feeds are built, but better missed at startup than on the first request.
+    util::read_expected_env_var("SOURCESHACK_DOMAIN");
 
-    rocket::custom(config.clone())
+    // All scripts, including git http-backend, share these limits and the cap on processes.
+    let cgi_limits = read_cgi_limits();
+
+    let mut rocket = rocket::custom(config.clone())
         .manage(config)
         .mount("/", routes::front_page::routes())
         .mount("/", routes::account::routes())
+        .mount("/", routes::settings::routes())
+        .mount("/", routes::repository::routes())
         .mount("/", routes::user::routes())
         .mount("/", routes::vcs::git::web::routes())
-        .mount("/", GitHttpBackend::new(data_dir.join("git_repos")))
-        .mount("/static", StaticFiles::from("static").rank(-100))
+        .mount("/", routes::vcs::git::raw::routes())
+        .mount("/", routes::vcs::git::archive::routes())
+        .mount("/", routes::vcs::git::commit_log::routes())
+        .mount("/", routes::vcs::git::commit::routes())
+        .mount("/", routes::vcs::git::patch::routes())
+        .mount("/", routes::vcs::git::refs::routes())
+        .mount("/", routes::vcs::git::blame::routes())
+        .mount("/", routes::vcs::git::compare::routes())
+        .mount("/", routes::vcs::git::feed::routes())
+        .mount(
+            "/",
+            GitHttpBackend::new(data_dir.join("git_repos")).limits(cgi_limits.clone()),
+        )
+        .mount("/static", StaticFiles::from("static").rank(-100));
+    // Other CGI programs, e.g. cgit, can be served next to sourceshack.
+    for (base, script_path) in util::read_cgi_scripts_env_var("SOURCESHACK_CGI_SCRIPTS") {
+        rocket = rocket.mount(
+            &base,
+            CgiHandler::new(script_path).limits(cgi_limits.clone()),
+        );
+    }
+    rocket
         .attach(Template::fairing())
         .attach(db::Postgres::fairing())
         .launch()
         .await
         .unwrap();
 }
+
+/// Reads the limits on CGI processes. Each one keeps its default when it is unset, and all but
+/// the number of processes can be lifted by setting them to `unlimited`. There is no limit on
+/// CPU time and address space unless one is configured.
+fn read_cgi_limits() -> CgiLimits {
+    let mut limits = match util::read_limit_env_var("SOURCESHACK_CGI_MAX_PROCESSES") {
+        Some(Some(0)) | Some(None) => panic!("SOURCESHACK_CGI_MAX_PROCESSES must be at least 1"),
+        Some(Some(max_processes)) => CgiLimits::new(max_processes as usize),
+        None => CgiLimits::default(),
+    };
+    if let Some(timeout) = util::read_limit_env_var("SOURCESHACK_CGI_TIMEOUT") {
+        limits = limits.timeout(timeout.map(Duration::from_secs));
+    }
+    if let Some(cpu_time) = util::read_limit_env_var("SOURCESHACK_CGI_CPU_TIME") {
+        limits = limits.cpu_time(cpu_time);
+    }
+    if let Some(address_space) = util::read_limit_env_var("SOURCESHACK_CGI_ADDRESS_SPACE") {
+        limits = limits.address_space(address_space);
+    }
+    limits
+}
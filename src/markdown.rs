@@ -0,0 +1,40 @@
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// Renders the Markdown in `source` to HTML that is safe to embed in a page.
+///
+/// `rewrite_url` is called with the destination of every link and image, and whether it is
+/// an image. If it returns a new destination, that is used instead.
+pub fn render<F>(source: &str, rewrite_url: F) -> String
+where
+    F: Fn(&str, bool) -> Option<String>,
+{
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let rewrite = |tag| match tag {
+        Tag::Link(link_type, destination, title) => {
+            let destination = rewrite_url(&destination, false)
+                .map(Into::into)
+                .unwrap_or(destination);
+            Tag::Link(link_type, destination, title)
+        }
+        Tag::Image(link_type, destination, title) => {
+            let destination = rewrite_url(&destination, true)
+                .map(Into::into)
+                .unwrap_or(destination);
+            Tag::Image(link_type, destination, title)
+        }
+        tag => tag,
+    };
+    let parser = Parser::new_ext(source, options).map(|event| match event {
+        Event::Start(tag) => Event::Start(rewrite(tag)),
+        event => event,
+    });
+
+    let mut unsafe_html = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, parser);
+    ammonia::clean(&unsafe_html)
+}
@@ -1,18 +1,37 @@
 use email_address::EmailAddress;
-use password_hash::{HasherError, PasswordHash, PasswordHasher, Salt, SaltString};
-use pbkdf2::Pbkdf2;
+use password_hash::SaltString;
 use rand_core::OsRng;
 use rocket::{
-    get, post,
+    get,
+    http::CookieJar,
+    post,
     request::{Form, FromForm},
+    response::Redirect,
     routes, Route,
 };
 use rocket_contrib::templates::Template;
 
-use crate::{db::Postgres, guards::AaudStr, util::tera_dummy_ctx};
+use crate::{
+    auth::{
+        password::{hash_password, verify_password},
+        session, totp, CurrentUser,
+    },
+    db::Postgres,
+    guards::AaudStr,
+    util::tera_dummy_ctx,
+};
 
 pub fn routes() -> Vec<Route> {
-    routes![sign_up, do_sign_up, sign_in, do_sign_in]
+    routes![
+        sign_up,
+        do_sign_up,
+        sign_in,
+        do_sign_in,
+        sign_in_second_factor,
+        do_sign_in_second_factor,
+        sign_out,
+        sign_out_everywhere
+    ]
 }
 
 #[get("/sign-up")]
@@ -65,16 +84,20 @@ fn sign_in() -> Template {
 }
 
 #[post("/sign-in", data = "<form>")]
-async fn do_sign_in<'r>(pg: Postgres<'r>, form: Form<SignIn>) -> Result<String, String> {
+async fn do_sign_in<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    form: Form<SignIn>,
+) -> Result<Redirect, String> {
     let username = form.login.clone();
     let emails: &[String] = &[form.login.clone()];
     let query_result = sqlx::query!(
         r#"
         SELECT
-            userid, password_hash
+            userid, password_hash, totp_secret
         FROM (
             SELECT
-                userid, username, emails, password_hash
+                userid, username, emails, password_hash, totp_secret
             FROM
                 public.users
             WHERE
@@ -87,12 +110,19 @@ async fn do_sign_in<'r>(pg: Postgres<'r>, form: Form<SignIn>) -> Result<String,
     .await
     .map_err(|err| format!("{:#?}", err))?;
     if let Some(user) = query_result {
-        let password_hash =
-            PasswordHash::new(&user.password_hash).map_err(|err| format!("{:#?}", err))?;
-        let login_hash = hash_password(form.password.as_bytes(), password_hash.salt.unwrap())
-            .map_err(|err| format!("{:#?}", err))?;
-        if login_hash.hash == password_hash.hash {
-            Ok(format!("Login successful"))
+        if verify_password(form.password.as_bytes(), &user.password_hash)
+            .map_err(|err| format!("{:#?}", err))?
+        {
+            if user.totp_secret.is_some() {
+                session::start_pending_sign_in(pg, cookies, user.userid)
+                    .await
+                    .map_err(|err| format!("{:#?}", err))?;
+                return Ok(Redirect::to("/sign-in/2fa"));
+            }
+            session::start_session(pg, cookies, user.userid)
+                .await
+                .map_err(|err| format!("{:#?}", err))?;
+            Ok(Redirect::to("/"))
         } else {
             Err(format!("Invalid password"))
         }
@@ -107,15 +137,94 @@ struct SignIn {
     password: String,
 }
 
-fn hash_password<'a>(password: &[u8], salt: Salt<'a>) -> Result<PasswordHash<'a>, HasherError> {
-    Pbkdf2.hash_password(
-        password,
-        None,
-        None,
-        pbkdf2::Params {
-            rounds: 10_000,
-            output_length: 32,
-        },
-        salt,
-    )
+#[get("/sign-in/2fa")]
+async fn sign_in_second_factor<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+) -> Result<Template, Result<Redirect, String>> {
+    let has_pending_sign_in = session::has_pending_sign_in(pg, cookies)
+        .await
+        .map_err(|err| Err(format!("{:#?}", err)))?;
+    if has_pending_sign_in {
+        Ok(Template::render("sign_in_2fa", tera_dummy_ctx()))
+    } else {
+        Err(Ok(Redirect::to("/sign-in")))
+    }
+}
+
+#[post("/sign-in/2fa", data = "<form>")]
+async fn do_sign_in_second_factor<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    form: Form<SecondFactor>,
+) -> Result<Redirect, String> {
+    let userid = match session::attempt_pending_sign_in(pg, cookies)
+        .await
+        .map_err(|err| format!("{:#?}", err))?
+    {
+        Some(userid) => userid,
+        None => return Ok(Redirect::to("/sign-in")),
+    };
+    let secret = totp::secret_for_userid(pg, userid)
+        .await
+        .map_err(|err| format!("{:#?}", err))?;
+    let is_valid = match secret {
+        Some(secret) => {
+            totp::use_code(pg, userid, &secret, &form.code)
+                .await
+                .map_err(|err| format!("{:#?}", err))?
+                || totp::use_recovery_code(pg, userid, &form.code)
+                    .await
+                    .map_err(|err| format!("{:#?}", err))?
+        }
+        // Two-factor authentication was disabled in the meantime.
+        None => true,
+    };
+    if is_valid {
+        session::end_pending_sign_in(pg, cookies)
+            .await
+            .map_err(|err| format!("{:#?}", err))?;
+        session::start_session(pg, cookies, userid)
+            .await
+            .map_err(|err| format!("{:#?}", err))?;
+        Ok(Redirect::to("/"))
+    } else {
+        Err(format!("Invalid authentication code"))
+    }
+}
+
+#[derive(Debug, FromForm)]
+struct SecondFactor {
+    code: String,
+}
+
+#[post("/sign-out")]
+async fn sign_out<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    user: Option<CurrentUser>,
+) -> Result<Redirect, String> {
+    if let Some(user) = user {
+        session::end_session(pg, cookies, user.session_id)
+            .await
+            .map_err(|err| format!("{:#?}", err))?;
+    }
+    Ok(Redirect::to("/"))
+}
+
+#[post("/sign-out-everywhere")]
+async fn sign_out_everywhere<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    user: Option<CurrentUser>,
+) -> Result<Redirect, String> {
+    if let Some(user) = user {
+        session::revoke_all_sessions(pg, user.userid)
+            .await
+            .map_err(|err| format!("{:#?}", err))?;
+        session::end_session(pg, cookies, user.session_id)
+            .await
+            .map_err(|err| format!("{:#?}", err))?;
+    }
+    Ok(Redirect::to("/"))
 }
@@ -2,17 +2,26 @@ use rocket::{get, routes, Route};
 use rocket_contrib::templates::Template;
 use serde::{Deserialize, Serialize};
 
+use crate::auth::CurrentUser;
+
 pub fn routes() -> Vec<Route> {
     routes![front_page]
 }
 
 #[get("/")]
-pub fn front_page() -> Template {
-    Template::render("front_page", FrontPageContext { users: vec![] })
+pub fn front_page(current_user: Option<CurrentUser>) -> Template {
+    Template::render(
+        "front_page",
+        FrontPageContext {
+            current_user: current_user.map(|user| user.username).unwrap_or_default(),
+            users: vec![],
+        },
+    )
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct FrontPageContext {
+    current_user: String,
     users: Vec<User>,
 }
 
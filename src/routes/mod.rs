@@ -1,4 +1,6 @@
 pub mod account;
 pub mod front_page;
+pub mod repository;
+pub mod settings;
 pub mod user;
 pub mod vcs;
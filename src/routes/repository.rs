@@ -0,0 +1,177 @@
+use std::fs;
+
+use git2::{Reference, Repository, RepositoryInitOptions};
+use log::error;
+use rocket::{
+    get,
+    http::Status,
+    post,
+    request::{Form, FromForm},
+    response::Redirect,
+    routes, Route,
+};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{token::ApiUser, CurrentUser},
+    db::Postgres,
+    guards::AaudStr,
+    routes::vcs::git::{git_repos_dir, repository_path},
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![new_repository, new_repository_signed_out, do_new_repository]
+}
+
+const DEFAULT_PRIMARY_BRANCH: &str = "main";
+
+#[get("/new")]
+fn new_repository(user: CurrentUser) -> Template {
+    Template::render(
+        "new_repository",
+        NewRepositoryPage {
+            current_user: user.username,
+            error: None,
+            name: String::new(),
+            description: String::new(),
+            primary_branch: DEFAULT_PRIMARY_BRANCH.to_string(),
+        },
+    )
+}
+
+#[get("/new", rank = 2)]
+fn new_repository_signed_out() -> Redirect {
+    Redirect::to("/sign-in")
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct NewRepositoryPage {
+    current_user: String,
+    error: Option<String>,
+    name: String,
+    description: String,
+    primary_branch: String,
+}
+
+/// Creates the database row and the bare repository on disk. If either step fails, the other
+/// one is undone so that the two never get out of sync.
+#[post("/new", data = "<form>")]
+async fn do_new_repository<'r>(
+    pg: Postgres<'r>,
+    user: ApiUser,
+    form: Form<NewRepository>,
+) -> Result<Redirect, Result<Template, Status>> {
+    let name = form.name.trim();
+    let description = form.description.trim();
+    let primary_branch = form.primary_branch.trim();
+    let invalid = |error: &str| {
+        Err(Ok(Template::render(
+            "new_repository",
+            NewRepositoryPage {
+                current_user: user.username.clone(),
+                error: Some(error.to_string()),
+                name: name.to_string(),
+                description: description.to_string(),
+                primary_branch: primary_branch.to_string(),
+            },
+        )))
+    };
+
+    if name.is_empty() || !AaudStr::is_valid(name) {
+        return invalid("Repository names may only contain ASCII letters, digits, '_' and '-'");
+    }
+    if !Reference::is_valid_name(&format!("refs/heads/{}", primary_branch)) {
+        return invalid("Invalid primary branch name");
+    }
+
+    let mut tx = pg.begin().await.map_err(|err| {
+        error!("Could not start transaction: {}", err);
+        Err(Status::InternalServerError)
+    })?;
+    let already_exists = sqlx::query!(
+        r#"
+        SELECT EXISTS(
+            SELECT
+                1
+            FROM
+                public.repositories
+            WHERE
+                owner_id = $1 AND repo_name = $2
+        ) AS "exists!"
+        "#,
+        user.userid,
+        name,
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(|err| {
+        error!("Could not query for repository {}/{}: {}", user.username, name, err);
+        Err(Status::InternalServerError)
+    })?
+    .exists;
+    if already_exists {
+        return invalid("You already have a repository with that name");
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO public.repositories
+            (repo_id, owner_id, vcs, repo_name, primary_branch, repo_description)
+        VALUES
+            (gen_random_uuid(), $1, 'git', $2, $3, $4)"#,
+        user.userid,
+        name,
+        primary_branch,
+        if description.is_empty() {
+            None
+        } else {
+            Some(description)
+        },
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(|err| {
+        error!("Could not insert repository {}/{}: {}", user.username, name, err);
+        Err(Status::InternalServerError)
+    })?;
+
+    let repo_path = repository_path(git_repos_dir(), &user.username, name);
+    if repo_path.exists() {
+        error!(
+            "{} exists on disk but has no database row",
+            repo_path.display()
+        );
+        return invalid("You already have a repository with that name");
+    }
+    if let Err(err) = Repository::init_opts(
+        &repo_path,
+        RepositoryInitOptions::new()
+            .bare(true)
+            .no_reinit(true)
+            .mkpath(true)
+            .initial_head(primary_branch)
+            .description(description),
+    ) {
+        error!("Could not create {}: {}", repo_path.display(), err);
+        // Dropping `tx` rolls back the insert.
+        let _ = fs::remove_dir_all(&repo_path);
+        return Err(Err(Status::InternalServerError));
+    }
+
+    if let Err(err) = tx.commit().await {
+        error!("Could not commit repository {}/{}: {}", user.username, name, err);
+        if let Err(err) = fs::remove_dir_all(&repo_path) {
+            error!("Could not remove {}: {}", repo_path.display(), err);
+        }
+        return Err(Err(Status::InternalServerError));
+    }
+
+    Ok(Redirect::to(format!("/~{}/{}", user.username, name)))
+}
+
+#[derive(Debug, FromForm)]
+struct NewRepository {
+    name: String,
+    description: String,
+    primary_branch: String,
+}
@@ -0,0 +1,275 @@
+use log::error;
+use rocket::{
+    get,
+    http::{Cookie, CookieJar, SameSite, Status},
+    post,
+    request::{Form, FromForm},
+    response::Redirect,
+    routes, Route,
+};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+
+use crate::{
+    auth::{
+        password::check_credentials,
+        token::{self, AccessToken, Scope},
+        totp, CurrentUser,
+    },
+    db::Postgres,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        settings,
+        settings_signed_out,
+        enrol_two_factor,
+        enrol_two_factor_signed_out,
+        do_enrol_two_factor,
+        disable_two_factor,
+        create_access_token,
+        revoke_access_token
+    ]
+}
+
+#[get("/settings")]
+async fn settings<'r>(pg: Postgres<'r>, user: CurrentUser) -> Result<Template, Status> {
+    let secret = totp::secret_for_userid(pg, user.userid)
+        .await
+        .map_err(|err| {
+            error!("Could not query for TOTP secret of {}: {}", user.userid, err);
+            Status::InternalServerError
+        })?;
+    let access_tokens = token::list_for_userid(pg, user.userid).await?;
+    Ok(Template::render(
+        "settings",
+        SettingsPage {
+            current_user: user.username,
+            two_factor_enabled: secret.is_some(),
+            access_tokens,
+        },
+    ))
+}
+
+#[get("/settings", rank = 2)]
+fn settings_signed_out() -> Redirect {
+    Redirect::to("/sign-in")
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SettingsPage {
+    current_user: String,
+    two_factor_enabled: bool,
+    access_tokens: Vec<AccessToken>,
+}
+
+#[get("/settings/2fa")]
+fn enrol_two_factor(cookies: &CookieJar<'_>, user: CurrentUser) -> Result<Template, Status> {
+    let secret = cookies
+        .get_private(totp::ENROLMENT_COOKIE)
+        .and_then(|cookie| totp::decode_secret(cookie.value()))
+        .unwrap_or_else(|| {
+            let secret = totp::generate_secret();
+            cookies.add_private(
+                Cookie::build(totp::ENROLMENT_COOKIE, totp::encode_secret(&secret))
+                    .path("/settings/2fa")
+                    .http_only(true)
+                    .same_site(SameSite::Strict)
+                    .finish(),
+            );
+            secret
+        });
+    let otpauth_uri = totp::otpauth_uri(&user.username, &secret);
+    let qr_code = totp::qr_code_svg(&otpauth_uri).ok_or_else(|| {
+        error!("Could not render QR code for {}", user.userid);
+        Status::InternalServerError
+    })?;
+    Ok(Template::render(
+        "two_factor_enrol",
+        TwoFactorEnrolPage {
+            current_user: user.username,
+            secret: totp::encode_secret(&secret),
+            otpauth_uri,
+            qr_code,
+        },
+    ))
+}
+
+#[get("/settings/2fa", rank = 2)]
+fn enrol_two_factor_signed_out() -> Redirect {
+    Redirect::to("/sign-in")
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct TwoFactorEnrolPage {
+    current_user: String,
+    secret: String,
+    otpauth_uri: String,
+    qr_code: String,
+}
+
+#[post("/settings/2fa", data = "<form>")]
+async fn do_enrol_two_factor<'r>(
+    pg: Postgres<'r>,
+    cookies: &CookieJar<'_>,
+    user: CurrentUser,
+    form: Form<ConfirmTwoFactor>,
+) -> Result<Template, Status> {
+    let secret = cookies
+        .get_private(totp::ENROLMENT_COOKIE)
+        .and_then(|cookie| totp::decode_secret(cookie.value()))
+        .ok_or(Status::BadRequest)?;
+    let step = totp::verify_code(&secret, &form.code).ok_or(Status::BadRequest)?;
+
+    let (recovery_codes, code_hashes) = totp::generate_recovery_codes().map_err(|err| {
+        error!("Could not hash recovery codes: {:?}", err);
+        Status::InternalServerError
+    })?;
+    totp::enable(pg, user.userid, &secret, step, &code_hashes)
+        .await
+        .map_err(|err| {
+            error!("Could not enable TOTP for {}: {}", user.userid, err);
+            Status::InternalServerError
+        })?;
+    cookies.remove_private(
+        Cookie::build(totp::ENROLMENT_COOKIE, "")
+            .path("/settings/2fa")
+            .finish(),
+    );
+
+    Ok(Template::render(
+        "recovery_codes",
+        RecoveryCodesPage {
+            current_user: user.username,
+            recovery_codes,
+        },
+    ))
+}
+
+#[derive(Debug, FromForm)]
+struct ConfirmTwoFactor {
+    code: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RecoveryCodesPage {
+    current_user: String,
+    recovery_codes: Vec<String>,
+}
+
+/// Turns two-factor authentication off. The user has to prove who they are again by entering
+/// both their password and a current authentication or recovery code.
+#[post("/settings/2fa/disable", data = "<form>")]
+async fn disable_two_factor<'r>(
+    pg: Postgres<'r>,
+    user: CurrentUser,
+    form: Form<DisableTwoFactor>,
+) -> Result<Redirect, Status> {
+    match check_credentials(pg, &user.username, &form.password).await? {
+        Some((userid, _)) if userid == user.userid => {}
+        _ => return Err(Status::Forbidden),
+    }
+    let secret = totp::secret_for_userid(pg, user.userid)
+        .await
+        .map_err(|err| {
+            error!("Could not query for TOTP secret of {}: {}", user.userid, err);
+            Status::InternalServerError
+        })?;
+    if let Some(secret) = secret {
+        let is_valid = totp::use_code(pg, user.userid, &secret, &form.code)
+            .await
+            .map_err(|err| {
+                error!("Could not check TOTP code of {}: {}", user.userid, err);
+                Status::InternalServerError
+            })?
+            || totp::use_recovery_code(pg, user.userid, &form.code)
+                .await
+                .map_err(|err| {
+                    error!("Could not check recovery code of {}: {}", user.userid, err);
+                    Status::InternalServerError
+                })?;
+        if !is_valid {
+            return Err(Status::Forbidden);
+        }
+        totp::disable(pg, user.userid).await.map_err(|err| {
+            error!("Could not disable TOTP for {}: {}", user.userid, err);
+            Status::InternalServerError
+        })?;
+    }
+    Ok(Redirect::to("/settings"))
+}
+
+#[derive(Debug, FromForm)]
+struct DisableTwoFactor {
+    password: String,
+    code: String,
+}
+
+/// Longest lifetime a personal access token may be given.
+const MAX_ACCESS_TOKEN_LIFETIME_DAYS: i32 = 366;
+
+#[post("/settings/tokens", data = "<form>")]
+async fn create_access_token<'r>(
+    pg: Postgres<'r>,
+    user: CurrentUser,
+    form: Form<CreateAccessToken>,
+) -> Result<Template, Status> {
+    let name = form.name.trim();
+    if name.is_empty()
+        || form.expires_in_days < 1
+        || form.expires_in_days > MAX_ACCESS_TOKEN_LIFETIME_DAYS
+    {
+        return Err(Status::BadRequest);
+    }
+    let scopes: Vec<Scope> = [
+        (form.repo_read, Scope::RepoRead),
+        (form.repo_write, Scope::RepoWrite),
+        (form.api, Scope::Api),
+    ]
+    .iter()
+    .filter(|(requested, _)| *requested)
+    .map(|(_, scope)| *scope)
+    .collect();
+    if scopes.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    let access_token =
+        token::create(pg, user.userid, name, &scopes, form.expires_in_days).await?;
+    Ok(Template::render(
+        "access_token_created",
+        AccessTokenCreatedPage {
+            current_user: user.username,
+            name: name.to_string(),
+            access_token,
+        },
+    ))
+}
+
+#[derive(Debug, FromForm)]
+struct CreateAccessToken {
+    name: String,
+    repo_read: bool,
+    repo_write: bool,
+    api: bool,
+    expires_in_days: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct AccessTokenCreatedPage {
+    current_user: String,
+    name: String,
+    access_token: String,
+}
+
+#[post("/settings/tokens/<token_id>/revoke")]
+async fn revoke_access_token<'r>(
+    pg: Postgres<'r>,
+    user: CurrentUser,
+    token_id: String,
+) -> Result<Redirect, Status> {
+    let token_id = Uuid::parse_str(&token_id).map_err(|_| Status::NotFound)?;
+    token::revoke(pg, user.userid, token_id).await?;
+    Ok(Redirect::to("/settings"))
+}
@@ -9,18 +9,25 @@ use rocket_contrib::templates::Template;
 use serde::{Deserialize, Serialize};
 use sqlx::types::Uuid;
 
-use crate::{db::Postgres, guards::UserNameGuard};
+use crate::{auth::CurrentUser, db::Postgres, guards::UserNameGuard};
 
 pub fn routes() -> Vec<Route> {
     routes![user]
 }
 
 #[get("/<username>")]
-async fn user<'r>(pg: Postgres<'r>, username: UserNameGuard<'r>) -> Result<Template, Status> {
+async fn user<'r>(
+    pg: Postgres<'r>,
+    current_user: Option<CurrentUser>,
+    username: UserNameGuard<'r>,
+) -> Result<Template, Status> {
     if let Some(userid) = userid_from_username(pg, username.as_ref()).await? {
         Ok(Template::render(
             "user",
             UserPage {
+                current_user: current_user
+                    .map(|current_user| current_user.username)
+                    .unwrap_or_default(),
                 username: username.to_string(),
                 repositories: repositories_for_userid(pg, userid).await,
             },
@@ -32,6 +39,7 @@ async fn user<'r>(pg: Postgres<'r>, username: UserNameGuard<'r>) -> Result<Templ
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct UserPage {
+    current_user: String,
     username: String,
     repositories: Vec<Repository>,
 }
@@ -0,0 +1,1136 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use flate2::{
+    write::{DeflateEncoder, GzEncoder},
+    Compression, Crc, CrcReader,
+};
+use git2::{FileMode, ObjectType, Oid, Repository, Tree};
+use lazy_static::lazy_static;
+use log::warn;
+use rand_core::{OsRng, RngCore};
+use rocket::{
+    get,
+    http::{ContentType, RawStr, Status},
+    request::FromParam,
+    response::{self, Responder},
+    routes, Request, Response, Route,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, DuplexStream},
+    runtime::Handle,
+    sync::Semaphore,
+};
+
+use crate::guards::{AaudStr, UserNameGuard};
+
+use super::{
+    data_dir, error::GitWebError, git_repos_dir, open_repository, repository_path, resolve_commit,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![archive]
+}
+
+/// Size of the buffer between the archive writer and the response.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Archives are expensive to generate, so only this many are generated at once.
+const MAX_CONCURRENT_ARCHIVES: usize = 4;
+/// How long a request waits for one of the other archives to finish before giving up.
+const MAX_QUEUE_TIME: Duration = Duration::from_secs(30);
+/// Once the cached archives take up more than this, the oldest ones are deleted.
+const MAX_CACHE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+lazy_static! {
+    static ref ARCHIVE_JOBS: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_CONCURRENT_ARCHIVES));
+}
+
+/// Where generated archives of tags are kept. Tags rarely move, so unlike branches their
+/// archives are worth keeping around.
+fn archive_cache_dir() -> PathBuf {
+    data_dir().join("archive_cache")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ArchiveFormat::TarGz => ContentType::new("application", "gzip"),
+            ArchiveFormat::Zip => ContentType::new("application", "zip"),
+        }
+    }
+}
+
+/// A path segment of the form `<rev>.tar.gz` or `<rev>.zip`.
+#[derive(Clone, Debug)]
+struct ArchiveRequest {
+    rev: String,
+    format: ArchiveFormat,
+}
+
+impl<'a> FromParam<'a> for ArchiveRequest {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        let decoded = param.percent_decode().map_err(|_| param)?;
+        let (rev, format) = if let Some(rev) = decoded.strip_suffix(".tar.gz") {
+            (rev, ArchiveFormat::TarGz)
+        } else if let Some(rev) = decoded.strip_suffix(".zip") {
+            (rev, ArchiveFormat::Zip)
+        } else {
+            return Err(param);
+        };
+        if rev.is_empty() {
+            return Err(param);
+        }
+        Ok(Self {
+            rev: rev.to_string(),
+            format,
+        })
+    }
+}
+
+#[get("/<owner>/<repo>/archive/<request>")]
+async fn archive<'r>(
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    request: ArchiveRequest,
+) -> Result<Archive, GitWebError> {
+    let (commit_id, is_tag) = {
+        let repository = open_repository(owner, repo.as_ref())?;
+        let commit_id = resolve_commit(&repository, &request.rev)?;
+        let is_tag = repository
+            .find_reference(&format!("refs/tags/{}", request.rev))
+            .and_then(|reference| reference.peel_to_commit())
+            .map(|commit| commit.id() == commit_id)
+            .unwrap_or(false);
+        (commit_id, is_tag)
+    };
+
+    let name = format!(
+        "{}-{}",
+        repo.as_ref(),
+        request.rev.replace(&['/', '\\', '"'][..], "-")
+    );
+    let file_name = format!("{}.{}", name, request.format.extension());
+    let cache_path = if is_tag {
+        Some(
+            archive_cache_dir()
+                .join(owner.as_ref())
+                .join(repo.as_ref())
+                .join(commit_id.to_string())
+                .join(&file_name),
+        )
+    } else {
+        None
+    };
+
+    if let Some(cache_path) = &cache_path {
+        if let Ok(file) = tokio::fs::File::open(cache_path).await {
+            return Ok(Archive {
+                file_name,
+                content_type: request.format.content_type(),
+                body: Box::pin(file),
+            });
+        }
+    }
+
+    let permit = tokio::time::timeout(MAX_QUEUE_TIME, ARCHIVE_JOBS.clone().acquire_owned())
+        .await
+        .map_err(|_| Status::ServiceUnavailable)?
+        .map_err(|_| Status::ServiceUnavailable)?;
+    let repo_dir = repository_path(git_repos_dir(), owner.as_ref(), repo.as_ref());
+    let (writer, reader) = tokio::io::duplex(CHUNK_SIZE);
+    let handle = Handle::current();
+    let format = request.format;
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let cache = cache_path.and_then(|path| match CacheFile::create(path) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!("Could not create archive cache file: {}", err);
+                None
+            }
+        });
+        let mut output = BufWriter::with_capacity(
+            CHUNK_SIZE,
+            ArchiveOutput {
+                handle,
+                writer,
+                cache,
+            },
+        );
+        let result = Repository::open_bare(&repo_dir)
+            .map_err(git_to_io_error)
+            .and_then(|repository| {
+                write_archive(&repository, commit_id, &name, format, &mut output)
+            })
+            .and_then(|()| {
+                output
+                    .into_inner()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+            });
+        match result {
+            Ok(output) => {
+                if let Some(cache) = output.cache {
+                    if let Err(err) = cache.persist() {
+                        warn!("Could not cache archive of {}: {}", commit_id, err);
+                    } else if let Err(err) = evict_cached_archives(&archive_cache_dir()) {
+                        warn!("Could not evict cached archives: {}", err);
+                    }
+                }
+            }
+            Err(err) => warn!("Could not archive {}: {}", commit_id, err),
+        }
+    });
+
+    Ok(Archive {
+        file_name,
+        content_type: request.format.content_type(),
+        body: Box::pin(reader),
+    })
+}
+
+struct Archive {
+    file_name: String,
+    content_type: ContentType,
+    body: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+impl<'r> Responder<'r, 'static> for Archive {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            )
+            .streamed_body(self.body)
+            .ok()
+    }
+}
+
+/// Forwards everything written to it to the response, and to the cache file if there is one.
+struct ArchiveOutput {
+    handle: Handle,
+    writer: DuplexStream,
+    cache: Option<CacheFile>,
+}
+
+impl Write for ArchiveOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // This fails when the client hung up, which ends the archive early.
+        self.handle.block_on(self.writer.write_all(buf))?;
+        if let Some(cache) = &mut self.cache {
+            if let Err(err) = cache.file.write_all(buf) {
+                warn!("Could not write archive cache file: {}", err);
+                self.cache = None;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.block_on(self.writer.flush())
+    }
+}
+
+/// An archive being written to the cache. It is written to a temporary file first, which
+/// is only moved into place once the archive is complete.
+struct CacheFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+}
+
+impl CacheFile {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(".{:016x}.tmp", OsRng.next_u64()));
+        let temp_path = path.with_file_name(temp_name);
+        let file = File::create(&temp_path)?;
+        Ok(Self {
+            path,
+            temp_path,
+            file,
+        })
+    }
+
+    fn persist(self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)
+    }
+}
+
+impl Drop for CacheFile {
+    fn drop(&mut self) {
+        // Does nothing once the file has been persisted.
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// Deletes the oldest archives in `cache_dir` until it is no larger than `MAX_CACHE_SIZE`.
+fn evict_cached_archives(cache_dir: &Path) -> io::Result<()> {
+    let mut archives = Vec::new();
+    list_cached_archives(cache_dir, &mut archives)?;
+    let mut cache_size: u64 = archives.iter().map(|(_, size, _)| size).sum();
+    if cache_size <= MAX_CACHE_SIZE {
+        return Ok(());
+    }
+
+    archives.sort_by_key(|(_, _, modified)| *modified);
+    for (path, size, _) in archives {
+        if cache_size <= MAX_CACHE_SIZE {
+            break;
+        }
+        match fs::remove_file(&path) {
+            // Another request may have gotten to it first.
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => cache_size -= size,
+        }
+        // Archives are stored below <owner>/<repo>/<commit>/, which are removed once empty.
+        for dir in path.ancestors().skip(1).take(3) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list_cached_archives(
+    dir: &Path,
+    archives: &mut Vec<(PathBuf, u64, SystemTime)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_cached_archives(&path, archives)?;
+        } else if path
+            .extension()
+            .map_or(true, |extension| extension != "tmp")
+        {
+            // Temporary files belong to archives that are still being written.
+            archives.push((path, metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
+
+fn git_to_io_error(err: git2::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+enum EntryKind {
+    Directory,
+    File { id: Oid, executable: bool },
+    Symlink { id: Oid },
+}
+
+struct ArchiveEntry {
+    /// Path below the archive's prefix, with '/' as the separator.
+    path: String,
+    kind: EntryKind,
+}
+
+fn write_archive<W: Write>(
+    repository: &Repository,
+    commit_id: Oid,
+    prefix: &str,
+    format: ArchiveFormat,
+    output: W,
+) -> io::Result<()> {
+    let commit = repository.find_commit(commit_id).map_err(git_to_io_error)?;
+    let mut attributes = ExportIgnore::default();
+    if let Ok(info_attributes) = fs::read(repository.path().join("info").join("attributes")) {
+        attributes.info = parse_attributes(&info_attributes, "");
+    }
+    let mut entries = Vec::new();
+    collect_entries(
+        repository,
+        &commit.tree().map_err(git_to_io_error)?,
+        "",
+        &mut attributes,
+        &mut entries,
+    )
+    .map_err(git_to_io_error)?;
+
+    let time = commit.time();
+    match format {
+        ArchiveFormat::TarGz => write_tar_gz(repository, prefix, time, &entries, output),
+        ArchiveFormat::Zip => write_zip(repository, prefix, time, &entries, output),
+    }
+}
+
+/// Lists the entries of `tree` and its subtrees in the order `git archive` writes them,
+/// leaving out everything marked `export-ignore`.
+fn collect_entries(
+    repository: &Repository,
+    tree: &Tree<'_>,
+    dir: &str,
+    attributes: &mut ExportIgnore,
+    entries: &mut Vec<ArchiveEntry>,
+) -> Result<(), git2::Error> {
+    let has_attributes_file = match tree.get_name(".gitattributes") {
+        Some(entry) if entry.kind() == Some(ObjectType::Blob) => {
+            let blob = repository.find_blob(entry.id())?;
+            attributes.dirs.push(parse_attributes(blob.content(), dir));
+            true
+        }
+        _ => false,
+    };
+
+    for entry in tree.iter() {
+        let path = format!("{}{}", dir, String::from_utf8_lossy(entry.name_bytes()));
+        let is_dir = entry.kind() == Some(ObjectType::Tree);
+        if attributes.is_ignored(&path, is_dir) {
+            continue;
+        }
+        match entry.filemode() {
+            mode if mode == i32::from(FileMode::Tree) => {
+                entries.push(ArchiveEntry {
+                    path: format!("{}/", path),
+                    kind: EntryKind::Directory,
+                });
+                let subtree = repository.find_tree(entry.id())?;
+                collect_entries(
+                    repository,
+                    &subtree,
+                    &format!("{}/", path),
+                    attributes,
+                    entries,
+                )?;
+            }
+            // Like `git archive`, submodules become empty directories.
+            mode if mode == i32::from(FileMode::Commit) => entries.push(ArchiveEntry {
+                path: format!("{}/", path),
+                kind: EntryKind::Directory,
+            }),
+            mode if mode == i32::from(FileMode::Link) => entries.push(ArchiveEntry {
+                path,
+                kind: EntryKind::Symlink { id: entry.id() },
+            }),
+            mode => entries.push(ArchiveEntry {
+                path,
+                kind: EntryKind::File {
+                    id: entry.id(),
+                    executable: mode == i32::from(FileMode::BlobExecutable),
+                },
+            }),
+        }
+    }
+
+    if has_attributes_file {
+        attributes.dirs.pop();
+    }
+    Ok(())
+}
+
+/// The `export-ignore` rules in effect while walking a tree: those from
+/// `$GIT_DIR/info/attributes`, then those of each `.gitattributes` from the root down.
+#[derive(Default)]
+struct ExportIgnore {
+    info: Vec<AttributeRule>,
+    dirs: Vec<Vec<AttributeRule>>,
+}
+
+struct AttributeRule {
+    /// The directory of the `.gitattributes` file, ending in '/' unless it is the root.
+    base: String,
+    pattern: String,
+    /// Whether `export-ignore` is set or unset by this rule.
+    ignore: bool,
+}
+
+impl ExportIgnore {
+    /// Applies the rules the way git does: `info/attributes` takes precedence, then deeper
+    /// files over shallower ones, and later lines over earlier ones.
+    fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        std::iter::once(&self.info)
+            .chain(self.dirs.iter().rev())
+            .flat_map(|rules| rules.iter().rev())
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| rule.ignore)
+            .unwrap_or(false)
+    }
+}
+
+fn parse_attributes(content: &[u8], base: &str) -> Vec<AttributeRule> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next().filter(|pattern| !pattern.starts_with('#'))?;
+            let ignore = fields
+                .filter_map(|attribute| match attribute {
+                    "export-ignore" => Some(true),
+                    "-export-ignore" | "!export-ignore" => Some(false),
+                    _ => None,
+                })
+                .last()?;
+            Some(AttributeRule {
+                base: base.to_string(),
+                pattern: pattern.to_string(),
+                ignore,
+            })
+        })
+        .collect()
+}
+
+impl AttributeRule {
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let path = match path.strip_prefix(self.base.as_str()) {
+            Some(path) => path,
+            None => return false,
+        };
+        let (pattern, dir_only) = match self.pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (self.pattern.as_str(), false),
+        };
+        if dir_only && !is_dir {
+            return false;
+        }
+        if pattern.contains('/') {
+            glob_match(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(pattern.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+/// Matches `text` against a gitignore style glob. `*` and `?` don't match '/', while `**`
+/// matches across directories.
+///
+/// Patterns come from the repository, so results are memoized on the position in the pattern
+/// and the text, which keeps patterns like `*a*a*a*a*b` from taking exponential time.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    Glob {
+        pattern,
+        text,
+        memo: vec![None; (pattern.len() + 1) * (text.len() + 1)],
+    }
+    .matches(0, 0)
+}
+
+struct Glob<'a> {
+    pattern: &'a [u8],
+    text: &'a [u8],
+    memo: Vec<Option<bool>>,
+}
+
+impl<'a> Glob<'a> {
+    /// Whether `text[t..]` matches `pattern[p..]`.
+    fn matches(&mut self, p: usize, t: usize) -> bool {
+        let key = p * (self.text.len() + 1) + t;
+        if let Some(matches) = self.memo[key] {
+            return matches;
+        }
+        let matches = self.matches_uncached(p, t);
+        self.memo[key] = Some(matches);
+        matches
+    }
+
+    fn matches_uncached(&mut self, p: usize, t: usize) -> bool {
+        let full_text = self.text;
+        let text = &full_text[t..];
+        match &self.pattern[p..] {
+            [] => text.is_empty(),
+            [b'*', b'*', b'/', ..] => {
+                self.matches(p + 3, t)
+                    || (t..full_text.len())
+                        .filter(|&idx| full_text[idx] == b'/')
+                        .any(|idx| self.matches(p + 3, idx + 1))
+            }
+            [b'*', b'*', ..] => (t..=full_text.len()).any(|idx| self.matches(p + 2, idx)),
+            [b'*', ..] => (t..=full_text.len())
+                .take_while(|&idx| idx == t || full_text[idx - 1] != b'/')
+                .any(|idx| self.matches(p + 1, idx)),
+            [b'?', ..] => match text {
+                [byte, ..] if *byte != b'/' => self.matches(p + 1, t + 1),
+                _ => false,
+            },
+            [b'[', rest @ ..] => {
+                let (negated, class_start) = match rest {
+                    [b'!', ..] | [b'^', ..] => (true, p + 2),
+                    _ => (false, p + 1),
+                };
+                let class = &self.pattern[class_start..];
+                // A ']' right at the start is part of the class.
+                let end = match class.iter().skip(1).position(|&byte| byte == b']') {
+                    Some(end) => end + 1,
+                    None => return matches!(text, [b'[', ..]) && self.matches(p + 1, t + 1),
+                };
+                match text {
+                    [byte, ..] if *byte != b'/' => {
+                        class_contains(&class[..end], *byte) != negated
+                            && self.matches(class_start + end + 1, t + 1)
+                    }
+                    _ => false,
+                }
+            }
+            [b'\\', literal, ..] => match text {
+                [byte, ..] if byte == literal => self.matches(p + 2, t + 1),
+                _ => false,
+            },
+            [literal, ..] => match text {
+                [byte, ..] if byte == literal => self.matches(p + 1, t + 1),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn class_contains(class: &[u8], byte: u8) -> bool {
+    let mut idx = 0;
+    while idx < class.len() {
+        if idx + 2 < class.len() && class[idx + 1] == b'-' {
+            if (class[idx]..=class[idx + 2]).contains(&byte) {
+                return true;
+            }
+            idx += 3;
+        } else {
+            if class[idx] == byte {
+                return true;
+            }
+            idx += 1;
+        }
+    }
+    false
+}
+
+/// Calls `f` with the size and a reader for the content of blob `id`. Loose objects are
+/// streamed from disk; packed ones can't be, so those are read into memory first.
+fn read_blob<T>(
+    repository: &Repository,
+    id: Oid,
+    f: impl FnOnce(u64, &mut dyn Read) -> io::Result<T>,
+) -> io::Result<T> {
+    let odb = repository.odb().map_err(git_to_io_error)?;
+    // `OdbReader` claims to fill the whole buffer on every read, even past the end of the
+    // object, so it has to be cut off at the object's size.
+    if let Ok((reader, size, _)) = odb.reader(id) {
+        return f(size as u64, &mut reader.take(size as u64));
+    }
+    let blob = repository.find_blob(id).map_err(git_to_io_error)?;
+    f(blob.size() as u64, &mut blob.content())
+}
+
+fn write_tar_gz<W: Write>(
+    repository: &Repository,
+    prefix: &str,
+    time: git2::Time,
+    entries: &[ArchiveEntry],
+    output: W,
+) -> io::Result<()> {
+    let mut builder = tar::Builder::new(GzEncoder::new(output, Compression::default()));
+    let mtime = time.seconds().max(0) as u64;
+    let header = |entry_type, mode| {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(0);
+        header.set_mtime(mtime);
+        header
+    };
+
+    let directory_header = || header(tar::EntryType::Directory, 0o775);
+    builder.append_data(&mut directory_header(), format!("{}/", prefix), io::empty())?;
+    for entry in entries {
+        let path = format!("{}/{}", prefix, entry.path);
+        match entry.kind {
+            EntryKind::Directory => {
+                builder.append_data(&mut directory_header(), path, io::empty())?;
+            }
+            EntryKind::File { id, executable } => {
+                let mode = if executable { 0o775 } else { 0o664 };
+                let mut header = header(tar::EntryType::Regular, mode);
+                read_blob(repository, id, |size, reader| {
+                    header.set_size(size);
+                    builder.append_data(&mut header, path, reader)
+                })?;
+            }
+            EntryKind::Symlink { id } => {
+                let blob = repository.find_blob(id).map_err(git_to_io_error)?;
+                let target = String::from_utf8_lossy(blob.content());
+                let mut header = header(tar::EntryType::Symlink, 0o777);
+                // Unlike `set_link_name`, this adds a GNU long link entry for long targets.
+                builder.append_link(&mut header, path, &*target)?;
+            }
+        }
+    }
+    builder.into_inner()?.finish()?.flush()
+}
+
+/// Counts the bytes written to `inner`, for the offsets and sizes in zip headers.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a zip file without seeking, which the `zip` crate can't do. Files are deflated
+/// while they're streamed out, so their CRC and sizes follow the data in a data descriptor.
+///
+/// There's no Zip64 support, so archives over 4 GiB or with more than 65535 entries fail.
+fn write_zip<W: Write>(
+    repository: &Repository,
+    prefix: &str,
+    time: git2::Time,
+    entries: &[ArchiveEntry],
+    output: W,
+) -> io::Result<()> {
+    let (dos_time, dos_date) = dos_date_time(time);
+    let too_large = || io::Error::new(io::ErrorKind::Other, "archive is too large for zip");
+    let to_u32 = |value: u64| u32::try_from(value).map_err(|_| too_large());
+    let mut output = CountingWriter {
+        inner: output,
+        count: 0,
+    };
+    let mut central_directory = Vec::new();
+    let mut entry_count = 0u16;
+
+    let directories = std::iter::once((format!("{}/", prefix), &EntryKind::Directory));
+    let entries = entries
+        .iter()
+        .map(|entry| (format!("{}/{}", prefix, entry.path), &entry.kind));
+    for (path, kind) in directories.chain(entries) {
+        let offset = to_u32(output.count)?;
+        let name_len = u16::try_from(path.len()).map_err(|_| too_large())?;
+        // The fields that local and central headers share.
+        let header_fields = |flags: u16, method: u16, crc: u32, compressed_size: u32, size: u32| {
+            let mut fields = Vec::with_capacity(26);
+            fields.extend_from_slice(&20u16.to_le_bytes()); // Version needed to extract
+            fields.extend_from_slice(&flags.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&dos_time.to_le_bytes());
+            fields.extend_from_slice(&dos_date.to_le_bytes());
+            fields.extend_from_slice(&crc.to_le_bytes());
+            fields.extend_from_slice(&compressed_size.to_le_bytes());
+            fields.extend_from_slice(&size.to_le_bytes());
+            fields.extend_from_slice(&name_len.to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+            fields
+        };
+
+        output.write_all(&0x04034b50u32.to_le_bytes())?;
+        let (fields, mode): (_, u32) = match *kind {
+            EntryKind::File { id, executable } => {
+                // Bit 3: the CRC and sizes follow the data. Bit 11: names are UTF-8.
+                output.write_all(&header_fields(0x0808, 8, 0, 0, 0))?;
+                output.write_all(path.as_bytes())?;
+                let start = output.count;
+                let (crc, size) = read_blob(repository, id, |size, reader| {
+                    let size = to_u32(size)?;
+                    let mut reader = CrcReader::new(reader);
+                    let mut encoder = DeflateEncoder::new(&mut output, Compression::default());
+                    io::copy(&mut reader, &mut encoder)?;
+                    encoder.finish()?;
+                    Ok((reader.crc().sum(), size))
+                })?;
+                let compressed_size = to_u32(output.count - start)?;
+                output.write_all(&0x08074b50u32.to_le_bytes())?;
+                output.write_all(&crc.to_le_bytes())?;
+                output.write_all(&compressed_size.to_le_bytes())?;
+                output.write_all(&size.to_le_bytes())?;
+                let mode = if executable { 0o100775 } else { 0o100664 };
+                (header_fields(0x0808, 8, crc, compressed_size, size), mode)
+            }
+            // Directories are empty and symlink targets are short and have to be stored as
+            // they are, so these go in uncompressed with everything known up front.
+            EntryKind::Directory | EntryKind::Symlink { .. } => {
+                let (content, mode) = match *kind {
+                    EntryKind::Symlink { id } => {
+                        let blob = repository.find_blob(id).map_err(git_to_io_error)?;
+                        (blob.content().to_vec(), 0o120777)
+                    }
+                    _ => (Vec::new(), 0o040775),
+                };
+                let mut crc = Crc::new();
+                crc.update(&content);
+                let size = to_u32(content.len() as u64)?;
+                let fields = header_fields(0x0800, 0, crc.sum(), size, size);
+                output.write_all(&fields)?;
+                output.write_all(path.as_bytes())?;
+                output.write_all(&content)?;
+                (fields, mode)
+            }
+        };
+        let external_attributes = (mode << 16) | if mode & 0o170000 == 0o040000 { 0x10 } else { 0 };
+
+        central_directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central_directory.extend_from_slice(&(3u16 << 8 | 20).to_le_bytes()); // Made by Unix
+        central_directory.extend_from_slice(&fields);
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Disk number
+        central_directory.extend_from_slice(&0u16.to_le_bytes()); // Internal attributes
+        central_directory.extend_from_slice(&external_attributes.to_le_bytes());
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(path.as_bytes());
+        entry_count = entry_count.checked_add(1).ok_or_else(too_large)?;
+    }
+
+    let central_directory_offset = to_u32(output.count)?;
+    let central_directory_size = to_u32(central_directory.len() as u64)?;
+    output.write_all(&central_directory)?;
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // Number of this disk
+    end.extend_from_slice(&0u16.to_le_bytes()); // Disk with the central directory
+    end.extend_from_slice(&entry_count.to_le_bytes());
+    end.extend_from_slice(&entry_count.to_le_bytes());
+    end.extend_from_slice(&central_directory_size.to_le_bytes());
+    end.extend_from_slice(&central_directory_offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+    output.write_all(&end)?;
+    output.flush()
+}
+
+/// The MS-DOS time and date fields of a zip entry for `time`, in the commit's time zone.
+fn dos_date_time(time: git2::Time) -> (u16, u16) {
+    let date_time = time::OffsetDateTime::from_unix_timestamp(time.seconds())
+        .to_offset(time::UtcOffset::minutes(time.offset_minutes() as i16));
+    // DOS dates start in 1980.
+    if date_time.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let dos_time = (date_time.hour() as u16) << 11
+        | (date_time.minute() as u16) << 5
+        | (date_time.second() as u16) / 2;
+    let dos_date = ((date_time.year() - 1980).min(127) as u16) << 9
+        | (date_time.month() as u16) << 5
+        | date_time.day() as u16;
+    (dos_time, dos_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_literals_and_wildcards() {
+        assert!(glob("README.md", "README.md"));
+        assert!(!glob("README.md", "README.mdx"));
+        assert!(glob("*.md", "README.md"));
+        assert!(glob("*", ""));
+        assert!(!glob("*.md", "docs/README.md"));
+        assert!(glob("?.txt", "a.txt"));
+        assert!(!glob("?.txt", "ab.txt"));
+        assert!(!glob("a?b", "a/b"));
+        assert!(glob("\\*.md", "*.md"));
+        assert!(!glob("\\*.md", "a.md"));
+    }
+
+    #[test]
+    fn glob_double_star() {
+        assert!(glob("**/test", "test"));
+        assert!(glob("**/test", "a/b/test"));
+        assert!(!glob("**/test", "a/b/contest"));
+        assert!(glob("docs/**", "docs/a/b.md"));
+        assert!(glob("a/**/b", "a/b"));
+        assert!(glob("a/**/b", "a/x/y/b"));
+        assert!(!glob("a/*/b", "a/x/y/b"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(glob("[abc].rs", "b.rs"));
+        assert!(!glob("[abc].rs", "d.rs"));
+        assert!(glob("[a-c]x", "bx"));
+        assert!(glob("[!a-c]x", "dx"));
+        assert!(!glob("[^a-c]x", "bx"));
+        assert!(glob("[]]", "]"));
+        assert!(!glob("[/]", "/"));
+        // An unterminated class is a literal '['.
+        assert!(glob("[ab", "[ab"));
+    }
+
+    /// A bare repository with a single commit of a few files, one of them export-ignored.
+    fn test_repository() -> (tempfile::TempDir, Repository, Oid) {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init_bare(dir.path()).unwrap();
+        let commit_id = {
+            let blob = |content: &[u8]| repository.blob(content).unwrap();
+            let mut bin = repository.treebuilder(None).unwrap();
+            bin.insert(
+                "run.sh",
+                blob(b"#!/bin/sh\necho hi\n"),
+                FileMode::BlobExecutable.into(),
+            )
+            .unwrap();
+            let bin = bin.write().unwrap();
+            let mut root = repository.treebuilder(None).unwrap();
+            root.insert(
+                ".gitattributes",
+                blob(b"*.tmp export-ignore\n"),
+                FileMode::Blob.into(),
+            )
+            .unwrap();
+            root.insert("README.md", blob(b"# Hello\n"), FileMode::Blob.into())
+                .unwrap();
+            root.insert("bin", bin, FileMode::Tree.into()).unwrap();
+            root.insert("link", blob(b"README.md"), FileMode::Link.into())
+                .unwrap();
+            root.insert("scratch.tmp", blob(b"scratch"), FileMode::Blob.into())
+                .unwrap();
+            let tree = repository.find_tree(root.write().unwrap()).unwrap();
+            let time = git2::Time::new(1_600_000_000, 60);
+            let signature = git2::Signature::new("Test", "test@example.com", &time).unwrap();
+            repository
+                .commit(None, &signature, &signature, "Initial commit", &tree, &[])
+                .unwrap()
+        };
+        (dir, repository, commit_id)
+    }
+
+    const ARCHIVE_PATHS: &[&str] = &[
+        "repo-v1/",
+        "repo-v1/.gitattributes",
+        "repo-v1/README.md",
+        "repo-v1/bin/",
+        "repo-v1/bin/run.sh",
+        "repo-v1/link",
+    ];
+
+    fn u16_at(bytes: &[u8], offset: usize) -> usize {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as usize
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]) as usize
+    }
+
+    #[test]
+    fn zip_archive_can_be_read() {
+        let (_dir, repository, commit_id) = test_repository();
+        let mut output = Vec::new();
+        write_archive(
+            &repository,
+            commit_id,
+            "repo-v1",
+            ArchiveFormat::Zip,
+            &mut output,
+        )
+        .unwrap();
+
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(&output)).unwrap();
+        let names: Vec<String> = (0..zip.len())
+            .map(|idx| zip.by_index(idx).unwrap().name().to_string())
+            .collect();
+        assert_eq!(names, ARCHIVE_PATHS);
+
+        let read = |zip: &mut zip::ZipArchive<_>, name| {
+            let mut file = zip.by_name(name).unwrap();
+            let mut content = Vec::new();
+            // Reading to the end also checks the CRC.
+            io::Read::read_to_end(&mut file, &mut content).unwrap();
+            (content, file.unix_mode())
+        };
+        assert_eq!(
+            read(&mut zip, "repo-v1/README.md"),
+            (b"# Hello\n".to_vec(), Some(0o100664))
+        );
+        assert_eq!(
+            read(&mut zip, "repo-v1/bin/run.sh"),
+            (b"#!/bin/sh\necho hi\n".to_vec(), Some(0o100775))
+        );
+        assert_eq!(
+            read(&mut zip, "repo-v1/link"),
+            (b"README.md".to_vec(), Some(0o120777))
+        );
+        assert!(zip.by_name("repo-v1/bin/").unwrap().is_dir());
+    }
+
+    #[test]
+    fn zip_central_directory_points_at_local_headers() {
+        let (_dir, repository, commit_id) = test_repository();
+        let mut output = Vec::new();
+        write_archive(
+            &repository,
+            commit_id,
+            "repo-v1",
+            ArchiveFormat::Zip,
+            &mut output,
+        )
+        .unwrap();
+
+        let end = output.len() - 22;
+        assert_eq!(u32_at(&output, end), 0x06054b50);
+        let entry_count = u16_at(&output, end + 10);
+        let central_directory_size = u32_at(&output, end + 12);
+        let central_directory_offset = u32_at(&output, end + 16);
+        assert_eq!(entry_count, ARCHIVE_PATHS.len());
+        assert_eq!(central_directory_offset + central_directory_size, end);
+
+        let mut pos = central_directory_offset;
+        for path in ARCHIVE_PATHS {
+            assert_eq!(u32_at(&output, pos), 0x02014b50);
+            let name_len = u16_at(&output, pos + 28);
+            let next = pos + 46 + name_len + u16_at(&output, pos + 30) + u16_at(&output, pos + 32);
+            let name = &output[pos + 46..pos + 46 + name_len];
+            assert_eq!(name, path.as_bytes());
+
+            let local = u32_at(&output, pos + 42);
+            assert_eq!(u32_at(&output, local), 0x04034b50);
+            let local_name_len = u16_at(&output, local + 26);
+            assert_eq!(&output[local + 30..local + 30 + local_name_len], name);
+            // Both headers agree on the flags, or the data descriptor after a streamed
+            // file's data has the CRC and the sizes.
+            assert_eq!(output[local + 6..local + 8], output[pos + 8..pos + 10]);
+            if u16_at(&output, pos + 8) & 0x08 == 0 {
+                assert_eq!(output[local + 14..local + 26], output[pos + 16..pos + 28]);
+            } else {
+                assert_eq!(u32_at(&output, local + 14), 0);
+                let compressed_size = u32_at(&output, pos + 20);
+                let descriptor = local + 30 + local_name_len + compressed_size;
+                assert_eq!(u32_at(&output, descriptor), 0x08074b50);
+                assert_eq!(
+                    output[descriptor + 4..descriptor + 16],
+                    output[pos + 16..pos + 28]
+                );
+            }
+            pos = next;
+        }
+        assert_eq!(pos, end);
+    }
+
+    #[test]
+    fn tar_gz_archive_can_be_read() {
+        let (_dir, repository, commit_id) = test_repository();
+        let mut output = Vec::new();
+        write_archive(
+            &repository,
+            commit_id,
+            "repo-v1",
+            ArchiveFormat::TarGz,
+            &mut output,
+        )
+        .unwrap();
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&output[..]));
+        let mut paths = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let header = entry.header();
+            assert_eq!(header.mtime().unwrap(), 1_600_000_000);
+            match path.as_str() {
+                "repo-v1/bin/run.sh" => assert_eq!(header.mode().unwrap(), 0o775),
+                "repo-v1/link" => {
+                    assert_eq!(header.entry_type(), tar::EntryType::Symlink);
+                    assert_eq!(
+                        entry.link_name().unwrap().unwrap().to_str(),
+                        Some("README.md")
+                    );
+                }
+                "repo-v1/README.md" => {
+                    let mut content = Vec::new();
+                    io::Read::read_to_end(&mut entry, &mut content).unwrap();
+                    assert_eq!(content, b"# Hello\n");
+                }
+                _ => {}
+            }
+            paths.push(path);
+        }
+        assert_eq!(paths, ARCHIVE_PATHS);
+    }
+
+    #[test]
+    fn tar_gz_archive_keeps_long_symlink_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = Repository::init_bare(dir.path()).unwrap();
+        let target = format!("{}/README.md", "nested".repeat(30));
+        let mut root = repository.treebuilder(None).unwrap();
+        let blob = repository.blob(target.as_bytes()).unwrap();
+        root.insert("link", blob, FileMode::Link.into()).unwrap();
+        let tree = repository.find_tree(root.write().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let commit_id = repository
+            .commit(None, &signature, &signature, "Link", &tree, &[])
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_archive(
+            &repository,
+            commit_id,
+            "repo-v1",
+            ArchiveFormat::TarGz,
+            &mut output,
+        )
+        .unwrap();
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&output[..]));
+        let link = archive
+            .entries()
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.path().unwrap().to_str() == Some("repo-v1/link"))
+            .unwrap();
+        assert_eq!(
+            link.link_name().unwrap().unwrap().to_str(),
+            Some(target.as_str())
+        );
+    }
+
+    #[test]
+    fn dos_date_time_uses_the_commit_time_zone() {
+        // 2020-09-13 12:26:40 UTC, recorded at +01:00.
+        let (dos_time, dos_date) = dos_date_time(git2::Time::new(1_600_000_000, 60));
+        assert_eq!(dos_time, (13 << 11) | (26 << 5) | (40 / 2));
+        assert_eq!(dos_date, (40 << 9) | (9 << 5) | 13);
+        assert_eq!(dos_date_time(git2::Time::new(0, 0)), (0, (1 << 5) | 1));
+    }
+
+    #[test]
+    fn glob_backtracking_is_not_exponential() {
+        let text = "a".repeat(100);
+        assert!(!glob("*a*a*a*a*a*a*a*a*a*a*b", &text));
+        assert!(!glob("**a**a**a**a**a**a**a**a**a**a**b", &text));
+        assert!(glob("*a*a*a*a*a*a*a*a*a*a*", &text));
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use git2::{BlameOptions, ObjectType, Oid, Repository};
+use rocket::{get, routes, Route};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    guards::{AaudStr, UserNameGuard},
+    highlight,
+};
+
+use super::{
+    error::GitWebError,
+    format_age, format_git_time, open_repository, path_to_string, resolve_commit,
+    tree_entry_kind_at,
+    web::{MAX_DISPLAYED_BLOB_SIZE, MAX_HIGHLIGHTED_BLOB_SIZE},
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![view_blame]
+}
+
+#[get("/<owner>/<repo>/blame/<rev>/<path..>")]
+fn view_blame(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    rev: String,
+    path: PathBuf,
+) -> Result<Template, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit_id = resolve_commit(&repository, &rev)?;
+    if tree_entry_kind_at(&repository, commit_id, &path)? != Some(ObjectType::Blob) {
+        return Err(GitWebError::NotFound);
+    }
+    let blob = repository
+        .find_commit(commit_id)?
+        .tree()?
+        .get_path(&path)?
+        .to_object(&repository)?
+        .peel_to_blob()?;
+
+    let (binary, too_large) = (blob.is_binary(), blob.size() > MAX_DISPLAYED_BLOB_SIZE);
+    let groups = if binary || too_large {
+        Vec::new()
+    } else {
+        let file_name = path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let content = String::from_utf8_lossy(blob.content());
+        let lines = if blob.size() > MAX_HIGHLIGHTED_BLOB_SIZE {
+            highlight::plain_lines(&content)
+        } else {
+            highlight::highlight_lines(&file_name, &content)
+        };
+        blame_groups(&repository, commit_id, &path, lines)?
+    };
+
+    let context = BlameInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        rev,
+        path: path_to_string(&path),
+        binary,
+        too_large,
+        groups,
+    };
+    Ok(Template::render("blame", context))
+}
+
+/// Blames the file at `path` as of `commit_id` and groups its `lines` by the commit that last
+/// changed them.
+fn blame_groups(
+    repository: &Repository,
+    commit_id: Oid,
+    path: &Path,
+    lines: Vec<String>,
+) -> Result<Vec<BlameGroup>, git2::Error> {
+    let mut options = BlameOptions::new();
+    options.newest_commit(commit_id);
+    let blame = repository.blame_file(path, Some(&mut options))?;
+
+    let mut commits: HashMap<Oid, BlameCommit> = HashMap::new();
+    let mut groups: Vec<BlameGroup> = Vec::new();
+    let mut previous_id = None;
+    for hunk in blame.iter() {
+        let start = hunk.final_start_line();
+        let hunk_lines = (start..start + hunk.lines_in_hunk()).filter_map(|number| {
+            lines.get(number - 1).map(|html| BlameLine {
+                number,
+                html: html.clone(),
+            })
+        });
+
+        let id = hunk.final_commit_id();
+        if previous_id == Some(id) {
+            if let Some(group) = groups.last_mut() {
+                group.lines.extend(hunk_lines);
+                continue;
+            }
+        }
+        previous_id = Some(id);
+
+        let commit = match commits.get(&id) {
+            Some(commit) => commit.clone(),
+            None => {
+                let commit = BlameCommit::new(repository, id)?;
+                commits.insert(id, commit.clone());
+                commit
+            }
+        };
+        // The line may have had a different path before the commit if the file was renamed.
+        let prior_path = hunk.path().unwrap_or(path);
+        // There is nothing to blame before the commit that added the file.
+        let prior_rev = match &commit.parent_id {
+            Some(parent_id) if has_path(repository, parent_id, prior_path)? => {
+                Some(parent_id.clone())
+            }
+            _ => None,
+        };
+        groups.push(BlameGroup {
+            prior_rev,
+            prior_path: path_to_string(prior_path),
+            prior_line: hunk.orig_start_line(),
+            commit,
+            lines: hunk_lines.collect(),
+        });
+    }
+    Ok(groups)
+}
+
+/// Whether the tree of the commit `commit_id` has anything at `path`.
+fn has_path(repository: &Repository, commit_id: &str, path: &Path) -> Result<bool, git2::Error> {
+    let tree = repository.find_commit(Oid::from_str(commit_id)?)?.tree()?;
+    match tree.get_path(path) {
+        Ok(_) => Ok(true),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BlameInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    rev: String,
+    path: String,
+    binary: bool,
+    too_large: bool,
+    groups: Vec<BlameGroup>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BlameGroup {
+    commit: BlameCommit,
+    /// Where to continue blaming these lines from, if the file existed before the commit.
+    prior_rev: Option<String>,
+    prior_path: String,
+    /// The line the group starts at in the commit, which is close to where it starts in the
+    /// prior revision. Line numbers as of `rev` can be far off once lines were added above.
+    prior_line: usize,
+    lines: Vec<BlameLine>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BlameCommit {
+    id: String,
+    short_id: String,
+    summary: String,
+    author: String,
+    date: String,
+    age: String,
+    parent_id: Option<String>,
+}
+
+impl BlameCommit {
+    fn new(repository: &Repository, id: Oid) -> Result<Self, git2::Error> {
+        let commit = repository.find_commit(id)?;
+        let id = id.to_string();
+        Ok(Self {
+            short_id: id[..7].to_string(),
+            id,
+            summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+                .into_owned(),
+            author: String::from_utf8_lossy(commit.author().name_bytes()).into_owned(),
+            date: format_git_time(commit.time()),
+            age: format_age(commit.time()),
+            parent_id: commit
+                .parent_id(0)
+                .ok()
+                .map(|parent_id| parent_id.to_string()),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BlameLine {
+    number: usize,
+    html: String,
+}
@@ -0,0 +1,145 @@
+use git2::{Commit, Oid, Repository, Signature};
+use log::warn;
+use rocket::{get, http::Status, routes, Route};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    guards::{AaudStr, UserNameGuard},
+};
+
+use super::{
+    diff::{self, RenderedDiff},
+    error::GitWebError,
+    format_git_time, open_repository,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![view_commit]
+}
+
+#[get("/<owner>/<repo>/commit/<id>", rank = 2)]
+fn view_commit(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    id: String,
+) -> Result<Template, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit = find_commit(&repository, &id)?;
+    let diff = RenderedDiff::new(&commit_diff(&repository, &commit)?)?;
+
+    let context = CommitInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        id: commit.id().to_string(),
+        parents: commit
+            .parent_ids()
+            .map(|parent_id| {
+                let id = parent_id.to_string();
+                ParentInfo {
+                    short_id: id[..7].to_string(),
+                    id,
+                }
+            })
+            .collect(),
+        author: SignatureInfo::new(&commit.author()),
+        committer: SignatureInfo::new(&commit.committer()),
+        summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+            .into_owned(),
+        body: String::from_utf8_lossy(commit.message_bytes())
+            .trim()
+            .splitn(2, '\n')
+            .nth(1)
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        diff,
+    };
+    Ok(Template::render("commit", context))
+}
+
+/// Looks up a commit by its full or abbreviated id.
+pub fn find_commit<'repo>(
+    repository: &'repo Repository,
+    id: &str,
+) -> Result<Commit<'repo>, Status> {
+    if id.len() < 4 || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Status::NotFound);
+    }
+    let commit = if id.len() == 40 {
+        Oid::from_str(id).and_then(|id| repository.find_commit(id))
+    } else {
+        repository
+            .revparse_single(id)
+            .and_then(|object| object.peel_to_commit())
+    };
+    commit.map_err(|err| match err.code() {
+        git2::ErrorCode::NotFound | git2::ErrorCode::Ambiguous | git2::ErrorCode::Peel => {
+            Status::NotFound
+        }
+        _ => {
+            warn!("Could not find commit {}: {}", id, err);
+            Status::InternalServerError
+        }
+    })
+}
+
+/// The changes `commit` introduced relative to its first parent, with renames detected.
+pub fn commit_diff<'repo>(
+    repository: &'repo Repository,
+    commit: &Commit<'repo>,
+) -> Result<git2::Diff<'repo>, git2::Error> {
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let mut diff = repository.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        Some(&mut diff::diff_options()),
+    )?;
+    diff::find_renames(&mut diff)?;
+    Ok(diff)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CommitInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    id: String,
+    parents: Vec<ParentInfo>,
+    author: SignatureInfo,
+    committer: SignatureInfo,
+    summary: String,
+    body: String,
+    diff: RenderedDiff,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ParentInfo {
+    id: String,
+    short_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SignatureInfo {
+    name: String,
+    email: String,
+    date: String,
+}
+
+impl SignatureInfo {
+    fn new(signature: &Signature<'_>) -> Self {
+        Self {
+            name: String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+            email: String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+            date: format_git_time(signature.when()),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+};
+
+use git2::{Commit, Delta, DiffFindOptions, Oid, Repository, Sort, Tree};
+use rocket::{get, http::Status, routes, Route};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    guards::{AaudStr, UserNameGuard},
+};
+
+use super::{error::GitWebError, format_git_time, open_repository, path_to_string, resolve_commit};
+
+pub fn routes() -> Vec<Route> {
+    routes![view_log_root, view_log]
+}
+
+/// Number of commits shown per page.
+const PAGE_SIZE: usize = 50;
+/// Upper bound on the commits inspected for a single page, so that filtering by a rarely
+/// touched path can't make one request walk the entire history.
+const MAX_SCANNED_COMMITS: usize = 10_000;
+
+/// The query of a log page, see `render_log`.
+struct LogQuery {
+    after: Option<String>,
+    before: Option<String>,
+    follow: Option<String>,
+    from: Option<String>,
+    from_follow: Option<String>,
+}
+
+/// `<rev>.atom` is handled by `feed::log_feed`.
+#[get(
+    "/<owner>/<repo>/log/<rev>?<after>&<before>&<follow>&<from>&<from_follow>",
+    rank = 2
+)]
+#[allow(clippy::too_many_arguments)]
+fn view_log_root(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    rev: String,
+    after: Option<String>,
+    before: Option<String>,
+    follow: Option<String>,
+    from: Option<String>,
+    from_follow: Option<String>,
+) -> Result<Template, GitWebError> {
+    let query = LogQuery {
+        after,
+        before,
+        follow,
+        from,
+        from_follow,
+    };
+    render_log(current_user, owner, repo, rev, PathBuf::new(), query)
+}
+
+#[get(
+    "/<owner>/<repo>/log/<rev>/<path..>?<after>&<before>&<follow>&<from>&<from_follow>",
+    rank = 3
+)]
+#[allow(clippy::too_many_arguments)]
+fn view_log(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    rev: String,
+    path: PathBuf,
+    after: Option<String>,
+    before: Option<String>,
+    follow: Option<String>,
+    from: Option<String>,
+    from_follow: Option<String>,
+) -> Result<Template, GitWebError> {
+    let query = LogQuery {
+        after,
+        before,
+        follow,
+        from,
+        from_follow,
+    };
+    render_log(current_user, owner, repo, rev, path, query)
+}
+
+/// Renders one page of the history of `rev`, optionally limited to commits touching `path`.
+///
+/// Pages are addressed by cursors rather than offsets. `after` lists the commits the walk
+/// continues from, i.e. the parents of the previous page that haven't been shown yet, and
+/// `follow` is the path the file had there if it was renamed. `from` and `from_follow` are the
+/// same for the page that linked to this one, so that the link back to it needs no search.
+///
+/// Without them, `before` is the first commit of the following page and is used to step back
+/// towards the tip, with `follow` being the path the file had at that commit. If the walk from
+/// the tip can't find it within `MAX_SCANNED_COMMITS`, the first page is shown instead.
+fn render_log(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    rev: String,
+    path: PathBuf,
+    query: LogQuery,
+) -> Result<Template, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let tip = resolve_commit(&repository, &rev)?;
+    let path = if path == Path::new("") {
+        None
+    } else {
+        Some(path)
+    };
+
+    let LogQuery {
+        after,
+        before,
+        follow,
+        from,
+        from_follow,
+    } = query;
+    let first_page = || -> Result<LogPage, GitWebError> {
+        LogWalk::new(&repository, &[tip], path.clone())?
+            .page(None)?
+            .ok_or_else(|| Status::BadRequest.into())
+    };
+    let (page, cursor) = match (after, before) {
+        (Some(after), _) => {
+            let starts = parse_cursor(&after).ok_or(Status::BadRequest)?;
+            let follow_path = follow.clone().map(PathBuf::from);
+            let mut page = LogWalk::new(&repository, &starts, path.clone())
+                .and_then(|walk| walk.following(follow_path).page(None))?
+                .ok_or(Status::BadRequest)?;
+            page.prev = match from.filter(|from| parse_cursor(from).is_some()) {
+                Some(from) => Some(PrevCursor::After {
+                    after: from,
+                    follow: from_follow,
+                }),
+                None => page.entries.first().map(|entry| PrevCursor::Before {
+                    before: entry.id.clone(),
+                    follow: follow.clone(),
+                }),
+            };
+            (page, Some((after, follow)))
+        }
+        (None, Some(before)) => {
+            let before = Oid::from_str(&before).map_err(|_| Status::BadRequest)?;
+            let before_path = follow.map(PathBuf::from).or_else(|| path.clone());
+            let page = LogWalk::new(&repository, &[tip], path.clone())
+                .and_then(|walk| walk.page(Some((before, before_path))))?;
+            match page {
+                Some(page) => (page, None),
+                None => (first_page()?, None),
+            }
+        }
+        (None, None) => (first_page()?, None),
+    };
+
+    let (next, next_follow) = match page.next {
+        Some(next) => (Some(next.after), next.follow),
+        None => (None, None),
+    };
+    let (prev_after, prev_before, prev_follow) = match page.prev {
+        Some(PrevCursor::After { after, follow }) => (Some(after), None, follow),
+        Some(PrevCursor::Before { before, follow }) => (None, Some(before), follow),
+        None => (None, None, None),
+    };
+    let (from, from_follow) = match cursor {
+        Some((after, follow)) => (Some(after), follow),
+        None => (None, None),
+    };
+    let context = LogInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        rev,
+        path: path.as_deref().map(path_to_string).unwrap_or_default(),
+        entries: page.entries,
+        next,
+        next_follow,
+        from,
+        from_follow,
+        prev_after,
+        prev_before,
+        prev_follow,
+    };
+    Ok(Template::render("log", context))
+}
+
+fn parse_cursor(cursor: &str) -> Option<Vec<Oid>> {
+    let starts = cursor
+        .split(',')
+        .map(|id| Oid::from_str(id).ok())
+        .collect::<Option<Vec<_>>>()?;
+    if starts.is_empty() {
+        None
+    } else {
+        Some(starts)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LogInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    rev: String,
+    path: String,
+    entries: Vec<LogEntry>,
+    next: Option<String>,
+    next_follow: Option<String>,
+    /// The cursor of this page, passed along to the next one.
+    from: Option<String>,
+    from_follow: Option<String>,
+    prev_after: Option<String>,
+    prev_before: Option<String>,
+    prev_follow: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct LogEntry {
+    id: String,
+    short_id: String,
+    summary: String,
+    author: String,
+    date: String,
+}
+
+impl LogEntry {
+    pub(super) fn new(commit: &Commit<'_>) -> Self {
+        let id = commit.id().to_string();
+        let author = commit.author();
+        Self {
+            short_id: id[..7].to_string(),
+            id,
+            summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+                .into_owned(),
+            author: String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            date: format_git_time(author.when()),
+        }
+    }
+}
+
+struct LogPage {
+    entries: Vec<LogEntry>,
+    next: Option<NextCursor>,
+    prev: Option<PrevCursor>,
+}
+
+struct NextCursor {
+    after: String,
+    follow: Option<String>,
+}
+
+enum PrevCursor {
+    /// The cursor of the previous page, if it is known.
+    After {
+        after: String,
+        follow: Option<String>,
+    },
+    /// The first commit of this page, to find the previous page by walking from the tip.
+    Before {
+        before: String,
+        follow: Option<String>,
+    },
+}
+
+/// A time ordered revwalk that keeps track of where it is in the history, so that it can
+/// stop after a page and hand out a cursor to continue from.
+struct LogWalk<'repo> {
+    repository: &'repo Repository,
+    revwalk: git2::Revwalk<'repo>,
+    starts: Vec<Oid>,
+    /// The path commits are filtered by. It changes when the walk follows a rename.
+    path: Option<PathBuf>,
+    original_path: Option<PathBuf>,
+}
+
+impl<'repo> LogWalk<'repo> {
+    fn new(
+        repository: &'repo Repository,
+        starts: &[Oid],
+        path: Option<PathBuf>,
+    ) -> Result<Self, git2::Error> {
+        let mut revwalk = repository.revwalk()?;
+        revwalk.set_sorting(Sort::TIME)?;
+        for start in starts {
+            revwalk.push(*start)?;
+        }
+        Ok(Self {
+            repository,
+            revwalk,
+            starts: starts.to_vec(),
+            original_path: path.clone(),
+            path,
+        })
+    }
+
+    /// Continues a walk that has already followed the file to `path`.
+    fn following(self, path: Option<PathBuf>) -> Self {
+        match path {
+            Some(path) => Self {
+                path: Some(path),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Walks until a page is full. If `until` is given, the walk instead stops right before
+    /// that commit and keeps only the last page worth of commits leading up to it.
+    ///
+    /// Returns `None` if the walk doesn't get to `until` within `MAX_SCANNED_COMMITS`, or gets
+    /// there following a different path than the one given along with it.
+    fn page(
+        mut self,
+        until: Option<(Oid, Option<PathBuf>)>,
+    ) -> Result<Option<LogPage>, git2::Error> {
+        let until_id = until.as_ref().map(|(id, _)| *id);
+        let mut processed = HashSet::new();
+        let mut pending: HashSet<Oid> = self.starts.iter().copied().collect();
+        let mut entries = VecDeque::with_capacity(PAGE_SIZE + 1);
+        let mut has_newer = false;
+        let mut stopped = false;
+        let mut reached_until = false;
+
+        for (scanned, id) in (&mut self.revwalk).enumerate() {
+            let id = id?;
+            let page_is_full = until.is_none() && entries.len() == PAGE_SIZE;
+            reached_until = Some(id) == until_id;
+            if page_is_full || reached_until || scanned == MAX_SCANNED_COMMITS {
+                stopped = true;
+                break;
+            }
+
+            let commit = self.repository.find_commit(id)?;
+            processed.insert(id);
+            pending.extend(commit.parent_ids());
+
+            let is_shown = match &self.path {
+                None => true,
+                Some(path) => touches(&commit, path)?,
+            };
+            if is_shown {
+                entries.push_back((LogEntry::new(&commit), self.follow()));
+                if entries.len() > PAGE_SIZE {
+                    entries.pop_front();
+                    has_newer = true;
+                }
+                if let Some(path) = &self.path {
+                    if let Some(old_path) = renamed_from(self.repository, &commit, path)? {
+                        self.path = Some(old_path);
+                    }
+                }
+            }
+        }
+
+        if let Some((_, until_path)) = &until {
+            if !reached_until || self.path != *until_path {
+                return Ok(None);
+            }
+        }
+
+        let next = if stopped {
+            let after: Vec<String> = pending
+                .difference(&processed)
+                .map(|id| id.to_string())
+                .collect();
+            if after.is_empty() {
+                None
+            } else {
+                Some(NextCursor {
+                    after: after.join(","),
+                    follow: self.follow(),
+                })
+            }
+        } else {
+            None
+        };
+        let prev = if has_newer {
+            entries.front().map(|(entry, follow)| PrevCursor::Before {
+                before: entry.id.clone(),
+                follow: follow.clone(),
+            })
+        } else {
+            None
+        };
+
+        Ok(Some(LogPage {
+            entries: entries.into_iter().map(|(entry, _)| entry).collect(),
+            next,
+            prev,
+        }))
+    }
+
+    /// The path the walk is at, if it differs from the one it started with.
+    fn follow(&self) -> Option<String> {
+        if self.path != self.original_path {
+            self.path.as_deref().map(path_to_string)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `commit` changed whatever is at `path`. Merges only count if they differ from
+/// every parent, the same way `git log <path>` treats them.
+fn touches(commit: &Commit<'_>, path: &Path) -> Result<bool, git2::Error> {
+    let entry_id = |tree: &Tree<'_>| tree.get_path(path).ok().map(|entry| entry.id());
+    let current = entry_id(&commit.tree()?);
+    if commit.parent_count() == 0 {
+        return Ok(current.is_some());
+    }
+    for parent in commit.parents() {
+        if entry_id(&parent.tree()?) == current {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// If `commit` created `path` by renaming another file, returns the file's previous path.
+fn renamed_from(
+    repository: &Repository,
+    commit: &Commit<'_>,
+    path: &Path,
+) -> Result<Option<PathBuf>, git2::Error> {
+    if commit.parent_count() != 1 {
+        return Ok(None);
+    }
+    let parent_tree = commit.parent(0)?.tree()?;
+    if parent_tree.get_path(path).is_ok() {
+        return Ok(None);
+    }
+    let mut diff =
+        repository.diff_tree_to_tree(Some(&parent_tree), Some(&commit.tree()?), None)?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+    Ok(diff
+        .deltas()
+        .filter(|delta| delta.status() == Delta::Renamed)
+        .find(|delta| delta.new_file().path() == Some(path))
+        .and_then(|delta| delta.old_file().path().map(Path::to_path_buf)))
+}
@@ -0,0 +1,190 @@
+use git2::{Oid, Repository, Sort};
+use rocket::{get, routes, Route};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    guards::{AaudStr, UserNameGuard},
+};
+
+use super::{
+    commit_log::LogEntry,
+    diff::{self, RenderedDiff},
+    error::GitWebError,
+    git_repos_dir, open_repository, repository_path, resolve_commit,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![view_compare]
+}
+
+/// Commits beyond this many are left out of the list, though their changes are still part of
+/// the diff.
+const MAX_LISTED_COMMITS: usize = 250;
+
+/// Compares `<base>...<head>` the way a merge would see it: the commits reachable from head but
+/// not from base, and the changes head made since the two diverged.
+///
+/// Head may be prefixed with `<owner>:` or `<owner>/<repo>:` to compare against a branch of
+/// another repository, such as a fork. Ranges ending in `.patch` or `.diff` are handled by
+/// `patch::compare_patch`.
+#[get("/<owner>/<repo>/compare/<range>", rank = 2)]
+fn view_compare(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    range: String,
+) -> Result<Template, GitWebError> {
+    let mut revs = range.splitn(2, "...");
+    let (base, head) = match (revs.next(), revs.next()) {
+        (Some(base), Some(head)) if !base.is_empty() && !head.is_empty() => (base, head),
+        _ => return Err(GitWebError::NotFound),
+    };
+    let head_spec = HeadSpec::parse(head, repo.as_ref())?;
+
+    let repository = open_repository(owner, repo.as_ref())?;
+    let base_id = resolve_commit(&repository, base)?;
+    let head_id = match &head_spec.repository {
+        Some((head_owner, head_repo)) => {
+            let head_repo_dir = repository_path(git_repos_dir(), head_owner, head_repo);
+            let head_repository = Repository::open_bare(head_repo_dir).map_err(|err| {
+                if err.code() == git2::ErrorCode::NotFound {
+                    GitWebError::NotFound
+                } else {
+                    err.into()
+                }
+            })?;
+            let head_id = resolve_commit(&head_repository, head_spec.rev)?;
+            // Lets the commits of the other repository be looked up through this one.
+            let objects_dir = head_repository.path().join("objects");
+            repository
+                .odb()?
+                .add_disk_alternate(&objects_dir.to_string_lossy())?;
+            head_id
+        }
+        None => resolve_commit(&repository, head_spec.rev)?,
+    };
+
+    let merge_base = match repository.merge_base(base_id, head_id) {
+        Ok(merge_base) => Some(merge_base),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let (commits, commits_truncated, diff) = match merge_base {
+        Some(merge_base) => {
+            let (commits, commits_truncated) = unique_commits(&repository, merge_base, head_id)?;
+            let diff = compare_diff(&repository, merge_base, head_id)?;
+            (commits, commits_truncated, Some(diff))
+        }
+        None => (Vec::new(), false, None),
+    };
+
+    let context = CompareInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        base: base.to_string(),
+        head: head.to_string(),
+        merge_base: merge_base.map(|merge_base| merge_base.to_string()),
+        commits,
+        commits_truncated,
+        diff,
+    };
+    Ok(Template::render("compare", context))
+}
+
+/// The head of a comparison, which may live in another repository.
+struct HeadSpec<'a> {
+    /// The owner and name of the other repository.
+    repository: Option<(&'a str, &'a str)>,
+    rev: &'a str,
+}
+
+impl<'a> HeadSpec<'a> {
+    /// Parses `<rev>`, `<owner>:<rev>` or `<owner>/<repo>:<rev>`. Ref names can't contain a
+    /// colon, so there's no ambiguity. Without a repository name, the other repository has the
+    /// same name as this one.
+    fn parse(head: &'a str, repo: &'a str) -> Result<Self, GitWebError> {
+        let (location, rev) = match head.find(':') {
+            Some(idx) => (&head[..idx], &head[idx + 1..]),
+            None => {
+                return Ok(Self {
+                    repository: None,
+                    rev: head,
+                })
+            }
+        };
+        let location = location.trim_start_matches('~');
+        let (head_owner, head_repo) = match location.find('/') {
+            Some(idx) => (&location[..idx], &location[idx + 1..]),
+            None => (location, repo),
+        };
+        // These end up in a file system path.
+        if head_owner.is_empty()
+            || head_repo.is_empty()
+            || rev.is_empty()
+            || !AaudStr::is_valid(head_owner)
+            || !AaudStr::is_valid(head_repo)
+        {
+            return Err(GitWebError::NotFound);
+        }
+        Ok(Self {
+            repository: Some((head_owner, head_repo)),
+            rev,
+        })
+    }
+}
+
+/// The commits reachable from `head_id` but not from `merge_base`, newest first, and whether
+/// there were more than `MAX_LISTED_COMMITS` of them.
+fn unique_commits(
+    repository: &Repository,
+    merge_base: Oid,
+    head_id: Oid,
+) -> Result<(Vec<LogEntry>, bool), git2::Error> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(head_id)?;
+    revwalk.hide(merge_base)?;
+    let mut commits = Vec::new();
+    for id in revwalk {
+        if commits.len() == MAX_LISTED_COMMITS {
+            return Ok((commits, true));
+        }
+        commits.push(LogEntry::new(&repository.find_commit(id?)?));
+    }
+    Ok((commits, false))
+}
+
+fn compare_diff(
+    repository: &Repository,
+    merge_base: Oid,
+    head_id: Oid,
+) -> Result<RenderedDiff, git2::Error> {
+    let base_tree = repository.find_commit(merge_base)?.tree()?;
+    let head_tree = repository.find_commit(head_id)?.tree()?;
+    let mut diff = repository.diff_tree_to_tree(
+        Some(&base_tree),
+        Some(&head_tree),
+        Some(&mut diff::diff_options()),
+    )?;
+    diff::find_renames(&mut diff)?;
+    RenderedDiff::new(&diff)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CompareInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    base: String,
+    head: String,
+    /// `None` if the two have no history in common.
+    merge_base: Option<String>,
+    commits: Vec<LogEntry>,
+    commits_truncated: bool,
+    diff: Option<RenderedDiff>,
+}
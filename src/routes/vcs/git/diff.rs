@@ -0,0 +1,199 @@
+use git2::{Delta, Diff, DiffFindOptions, DiffLineType, DiffOptions, Patch};
+use serde::{Deserialize, Serialize};
+
+/// Files larger than this are treated as binary and never diffed, so that a single huge
+/// generated file can't make rendering a diff arbitrarily expensive.
+const MAX_DIFFED_FILE_SIZE: i64 = 1024 * 1024;
+/// Files beyond this many are listed in the diffstat but their changes are not shown.
+const MAX_RENDERED_FILES: usize = 300;
+/// A file with more changed lines than this is collapsed to a notice.
+const MAX_RENDERED_LINES_PER_FILE: usize = 2_000;
+/// Once this many lines have been rendered, the remaining files are collapsed as well.
+const MAX_RENDERED_LINES: usize = 20_000;
+
+/// Options for diffing two trees with the limits above applied.
+pub fn diff_options() -> DiffOptions {
+    let mut options = DiffOptions::new();
+    options.max_size(MAX_DIFFED_FILE_SIZE);
+    options
+}
+
+/// Turns on rename and copy detection for `diff`.
+pub fn find_renames(diff: &mut Diff<'_>) -> Result<(), git2::Error> {
+    diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))
+}
+
+/// Whether `diff` is larger than a rendered diff can show in full, for output that can't leave
+/// anything out, like patches.
+pub fn exceeds_limits(diff: &Diff<'_>) -> Result<bool, git2::Error> {
+    if diff.deltas().len() > MAX_RENDERED_FILES {
+        return Ok(true);
+    }
+    let stats = diff.stats()?;
+    Ok(stats.insertions() + stats.deletions() > MAX_RENDERED_LINES)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RenderedDiff {
+    pub files: Vec<DiffFile>,
+    /// Lines added in the files that were diffed, which is all of them unless `truncated`.
+    pub additions: usize,
+    pub deletions: usize,
+    /// Whether some files were left out because the diff is too large.
+    pub truncated: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiffFile {
+    pub old_path: String,
+    pub new_path: String,
+    pub status: String,
+    pub additions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+    /// The changes are not shown because the file or the diff as a whole is too large.
+    pub collapsed: bool,
+    /// The file wasn't diffed at all because the diff as a whole is too large, so `additions`
+    /// and `deletions` are unknown.
+    pub skipped: bool,
+    pub hunks: Vec<DiffHunk>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiffHunk {
+    pub header: String,
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiffLine {
+    pub kind: String,
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+    pub content: String,
+}
+
+impl RenderedDiff {
+    pub fn new(diff: &Diff<'_>) -> Result<Self, git2::Error> {
+        let mut files = Vec::with_capacity(diff.deltas().len());
+        let mut additions = 0;
+        let mut deletions = 0;
+        let mut rendered_lines = 0;
+        let mut truncated = false;
+
+        for (idx, delta) in diff.deltas().enumerate() {
+            let path_of = |file: git2::DiffFile<'_>| {
+                file.path()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            };
+            let mut file = DiffFile {
+                old_path: path_of(delta.old_file()),
+                new_path: path_of(delta.new_file()),
+                status: status_name(delta.status()).to_string(),
+                additions: 0,
+                deletions: 0,
+                binary: delta.flags().is_binary(),
+                collapsed: false,
+                skipped: false,
+                hunks: Vec::new(),
+            };
+
+            // Past the limits, files are only listed. Diffing them would cost as much as
+            // rendering them.
+            if files.len() >= MAX_RENDERED_FILES || rendered_lines >= MAX_RENDERED_LINES {
+                file.collapsed = true;
+                file.skipped = true;
+                truncated = true;
+                files.push(file);
+                continue;
+            }
+
+            let patch = match Patch::from_diff(diff, idx)? {
+                Some(patch) => patch,
+                None => {
+                    files.push(file);
+                    continue;
+                }
+            };
+            let (_, file_additions, file_deletions) = patch.line_stats()?;
+            file.additions = file_additions;
+            file.deletions = file_deletions;
+            file.binary |= patch.delta().flags().is_binary();
+            additions += file_additions;
+            deletions += file_deletions;
+
+            let changed_lines = file_additions + file_deletions;
+            if changed_lines > MAX_RENDERED_LINES_PER_FILE {
+                file.collapsed = true;
+            } else if !file.binary {
+                for hunk_idx in 0..patch.num_hunks() {
+                    let (hunk, line_count) = patch.hunk(hunk_idx)?;
+                    let mut lines = Vec::with_capacity(line_count);
+                    for line_idx in 0..line_count {
+                        let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+                        let kind = line_kind(line.origin_value());
+                        let content = String::from_utf8_lossy(line.content());
+                        // The "\ No newline at end of file" marker comes wrapped in newlines.
+                        let content = if kind == "no-newline" {
+                            content.trim_matches(&['\r', '\n'][..])
+                        } else {
+                            content.trim_end_matches(&['\r', '\n'][..])
+                        };
+                        lines.push(DiffLine {
+                            kind: kind.to_string(),
+                            old_lineno: line.old_lineno(),
+                            new_lineno: line.new_lineno(),
+                            content: content.to_string(),
+                        });
+                    }
+                    rendered_lines += lines.len();
+                    file.hunks.push(DiffHunk {
+                        header: String::from_utf8_lossy(hunk.header())
+                            .trim_end()
+                            .to_string(),
+                        lines,
+                    });
+                }
+            }
+            files.push(file);
+        }
+
+        Ok(Self {
+            files,
+            additions,
+            deletions,
+            truncated,
+        })
+    }
+}
+
+fn status_name(status: Delta) -> &'static str {
+    match status {
+        Delta::Added => "added",
+        Delta::Deleted => "deleted",
+        Delta::Modified => "modified",
+        Delta::Renamed => "renamed",
+        Delta::Copied => "copied",
+        Delta::Typechange => "typechange",
+        Delta::Unmodified
+        | Delta::Ignored
+        | Delta::Untracked
+        | Delta::Unreadable
+        | Delta::Conflicted => "unmodified",
+    }
+}
+
+fn line_kind(line_type: DiffLineType) -> &'static str {
+    match line_type {
+        DiffLineType::Addition => "addition",
+        DiffLineType::Deletion => "deletion",
+        DiffLineType::ContextEOFNL | DiffLineType::AddEOFNL | DiffLineType::DeleteEOFNL => {
+            "no-newline"
+        }
+        DiffLineType::Context
+        | DiffLineType::FileHeader
+        | DiffLineType::HunkHeader
+        | DiffLineType::Binary => "context",
+    }
+}
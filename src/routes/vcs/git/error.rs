@@ -0,0 +1,99 @@
+use std::fmt;
+
+use log::error;
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    Request, Response,
+};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::CurrentUser;
+
+/// Why a page of the repository browser could not be shown.
+#[derive(Debug)]
+pub enum GitWebError {
+    /// The repository, revision or path doesn't exist.
+    NotFound,
+    /// Reading from the repository failed, which usually means it is corrupt.
+    Git(git2::Error),
+    /// The output would be too large to produce without leaving anything out.
+    TooLarge,
+    /// A helper already logged what went wrong and picked a status.
+    Status(Status),
+}
+
+impl GitWebError {
+    pub fn status(&self) -> Status {
+        match self {
+            Self::NotFound => Status::NotFound,
+            Self::Git(_) => Status::InternalServerError,
+            Self::TooLarge => Status::UnprocessableEntity,
+            Self::Status(status) => *status,
+        }
+    }
+}
+
+impl fmt::Display for GitWebError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Not found"),
+            Self::Git(err) => write!(f, "Error while reading repository: {}", err),
+            Self::TooLarge => write!(f, "Too large"),
+            Self::Status(status) => write!(f, "{}", status),
+        }
+    }
+}
+
+impl std::error::Error for GitWebError {}
+
+impl From<git2::Error> for GitWebError {
+    fn from(err: git2::Error) -> Self {
+        Self::Git(err)
+    }
+}
+
+impl From<Status> for GitWebError {
+    fn from(status: Status) -> Self {
+        if status == Status::NotFound {
+            Self::NotFound
+        } else {
+            Self::Status(status)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ErrorInfo {
+    current_user: String,
+    status: String,
+    message: String,
+}
+
+impl<'r> Responder<'r, 'static> for GitWebError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let message = match &self {
+            Self::NotFound => "There is nothing here. The repository, revision or path may have \
+                               been misspelled, or it may have been removed."
+                .to_string(),
+            Self::Git(_) => {
+                error!("{} {}: {}", request.method(), request.uri(), self);
+                "The repository could not be read.".to_string()
+            }
+            Self::TooLarge => "This is too large to be shown in full.".to_string(),
+            Self::Status(_) => String::new(),
+        };
+        let context = ErrorInfo {
+            current_user: CurrentUser::cached(request)
+                .map(|current_user| current_user.username)
+                .unwrap_or_default(),
+            status: status.to_string(),
+            message,
+        };
+        Response::build_from(Template::render("error", context).respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
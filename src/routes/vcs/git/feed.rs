@@ -0,0 +1,381 @@
+use std::env;
+
+use git2::{Commit, Oid, Repository, Signature, Sort};
+use log::{error, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rocket::{
+    get,
+    http::{ContentType, RawStr, Status},
+    request::FromParam,
+    response::content::Content,
+    routes, Route,
+};
+
+use crate::{
+    db::Postgres,
+    guards::{AaudStr, UserNameGuard},
+    highlight::escape_html,
+    routes::user::userid_from_username,
+};
+
+use super::{
+    error::GitWebError, git_repos_dir, open_repository, primary_branch_tip, repository_path,
+    resolve_commit,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![log_feed, tags_feed, user_feed]
+}
+
+/// Number of entries in a feed.
+const FEED_LENGTH: usize = 30;
+
+/// A path segment of the form `<rev>.atom`. Anything else is forwarded to the HTML log.
+#[derive(Clone, Debug)]
+struct AtomRev(String);
+
+impl<'a> FromParam<'a> for AtomRev {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        let decoded = param.percent_decode().map_err(|_| param)?;
+        match decoded.strip_suffix(".atom") {
+            Some(rev) if !rev.is_empty() => Ok(Self(rev.to_string())),
+            _ => Err(param),
+        }
+    }
+}
+
+/// A path segment of the form `~<user>.atom`.
+#[derive(Clone, Copy, Debug)]
+struct UserFeedName<'a>(UserNameGuard<'a>);
+
+impl<'a> FromParam<'a> for UserFeedName<'a> {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        let name = param.as_str().strip_suffix(".atom").ok_or(param)?;
+        UserNameGuard::from_param(RawStr::from_str(name))
+            .map(Self)
+            .map_err(|_| param)
+    }
+}
+
+type AtomResponse = Content<String>;
+
+fn atom(xml: String) -> AtomResponse {
+    Content(ContentType::new("application", "atom+xml"), xml)
+}
+
+/// Where sourceshack is served from, e.g. `https://sourceshack.example`. Feed and entry ids
+/// have to stay the same whichever name a feed is fetched by, so they are built from the
+/// configured domain rather than from the `Host` header.
+fn base_url() -> String {
+    let domain = env::var("SOURCESHACK_DOMAIN").expect("SOURCESHACK_DOMAIN is not set");
+    format!("https://{}", domain.trim_end_matches('/'))
+}
+
+/// The commits of `<rev>`, newest first.
+#[get("/<owner>/<repo>/log/<rev>")]
+fn log_feed(
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    rev: AtomRev,
+) -> Result<AtomResponse, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit_id = resolve_commit(&repository, &rev.0)?;
+    let repo_url = format!("{}/~{}/{}", base_url(), owner, repo);
+    let log_url = format!(
+        "{}/log/{}",
+        repo_url,
+        utf8_percent_encode(&rev.0, NON_ALPHANUMERIC)
+    );
+
+    let mut entries = Vec::new();
+    for commit in recent_commits(&repository, commit_id)? {
+        entries.push(FeedEntry::for_commit(&repo_url, &commit, None));
+    }
+
+    let feed = Feed {
+        id: log_url.clone(),
+        title: format!("~{}/{}: history of {}", owner, repo, rev.0),
+        self_url: format!("{}.atom", log_url),
+        alternate_url: log_url,
+        entries,
+    };
+    Ok(atom(feed.to_xml()))
+}
+
+/// The tags that point at commits, most recently tagged first.
+#[get("/<owner>/<repo>/refs/tags.atom")]
+fn tags_feed(owner: UserNameGuard<'_>, repo: AaudStr<'_>) -> Result<AtomResponse, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let repo_url = format!("{}/~{}/{}", base_url(), owner, repo);
+
+    let mut entries = Vec::new();
+    for reference in repository.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let name = String::from_utf8_lossy(reference.shorthand_bytes()).into_owned();
+        let url = format!(
+            "{}/tree/{}",
+            repo_url,
+            utf8_percent_encode(&name, NON_ALPHANUMERIC)
+        );
+        // Annotated tags say who tagged them and when, lightweight tags only have the commit.
+        let tag = reference.peel_to_tag().ok();
+        let tagger = tag.as_ref().and_then(|tag| tag.tagger());
+        let signature = tagger.unwrap_or_else(|| commit.author());
+        let content = tag
+            .as_ref()
+            .and_then(|tag| tag.message_bytes())
+            .map(|message| String::from_utf8_lossy(message).trim().to_string())
+            .unwrap_or_else(|| commit_message(&commit));
+        entries.push(FeedEntry {
+            id: url.clone(),
+            title: name,
+            url,
+            updated: signature.when(),
+            published: None,
+            author: author(&signature),
+            content,
+        });
+    }
+    entries.sort_by(|a, b| b.updated.seconds().cmp(&a.updated.seconds()));
+    entries.truncate(FEED_LENGTH);
+
+    let refs_url = format!("{}/refs", repo_url);
+    let feed = Feed {
+        id: format!("{}#tags", refs_url),
+        title: format!("~{}/{}: tags", owner, repo),
+        self_url: format!("{}/tags.atom", refs_url),
+        alternate_url: refs_url,
+        entries,
+    };
+    Ok(atom(feed.to_xml()))
+}
+
+/// The most recent commits to the primary branches of all repositories of a user.
+#[get("/<user>", rank = 2)]
+async fn user_feed<'r>(
+    pg: Postgres<'r>,
+    user: UserFeedName<'r>,
+) -> Result<AtomResponse, GitWebError> {
+    let owner = user.0;
+    let userid = userid_from_username(pg, owner.as_str())
+        .await?
+        .ok_or(GitWebError::NotFound)?;
+    let repositories = sqlx::query!(
+        r#"
+        SELECT
+            repo_name, primary_branch
+        FROM
+            public.repositories
+        WHERE
+            owner_id = $1 AND vcs = 'git'
+        "#,
+        userid,
+    )
+    .fetch_all(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for repositories of {}: {}", owner, err);
+        Status::InternalServerError
+    })?;
+
+    let user_url = format!("{}/~{}", base_url(), owner);
+    let mut entries = Vec::new();
+    for row in repositories {
+        let repo_dir = repository_path(git_repos_dir(), owner.as_ref(), &row.repo_name);
+        let commits = Repository::open_bare(&repo_dir).and_then(|repository| {
+            // Empty repositories have no commits yet.
+            let commit_id = match primary_branch_tip(&repository, Some(&row.primary_branch)) {
+                Some((_, commit_id)) => commit_id,
+                None => return Ok(Vec::new()),
+            };
+            let repo_url = format!("{}/{}", user_url, row.repo_name);
+            recent_commits(&repository, commit_id).map(|commits| {
+                commits
+                    .iter()
+                    .map(|commit| FeedEntry::for_commit(&repo_url, commit, Some(&row.repo_name)))
+                    .collect::<Vec<_>>()
+            })
+        });
+        match commits {
+            Ok(commits) => entries.extend(commits),
+            Err(err) => warn!("Could not read history of {}: {}", repo_dir.display(), err),
+        }
+    }
+    entries.sort_by(|a, b| b.updated.seconds().cmp(&a.updated.seconds()));
+    entries.truncate(FEED_LENGTH);
+
+    let feed = Feed {
+        id: user_url.clone(),
+        title: format!("~{}", owner),
+        self_url: format!("{}.atom", user_url),
+        alternate_url: user_url,
+        entries,
+    };
+    Ok(atom(feed.to_xml()))
+}
+
+/// Up to `FEED_LENGTH` commits reachable from `commit_id`, newest first.
+fn recent_commits(repository: &Repository, commit_id: Oid) -> Result<Vec<Commit<'_>>, git2::Error> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(commit_id)?;
+    revwalk
+        .take(FEED_LENGTH)
+        .map(|id| repository.find_commit(id?))
+        .collect()
+}
+
+fn commit_message(commit: &Commit<'_>) -> String {
+    String::from_utf8_lossy(commit.message_bytes())
+        .trim()
+        .to_string()
+}
+
+fn author(signature: &Signature<'_>) -> Author {
+    Author {
+        name: String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+        email: String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+    }
+}
+
+/// Formats a git time as RFC 3339, as Atom requires, keeping the time zone it was recorded in.
+fn format_atom_time(time: git2::Time) -> String {
+    time::OffsetDateTime::from_unix_timestamp(time.seconds())
+        .to_offset(time::UtcOffset::minutes(time.offset_minutes() as i16))
+        .format(time::Format::Rfc3339)
+}
+
+struct Feed {
+    /// A URL that identifies the feed, which is also where it is shown as a web page.
+    id: String,
+    title: String,
+    self_url: String,
+    alternate_url: String,
+    entries: Vec<FeedEntry>,
+}
+
+struct FeedEntry {
+    /// A URL that identifies the entry and doesn't change when it is regenerated.
+    id: String,
+    title: String,
+    url: String,
+    updated: git2::Time,
+    published: Option<git2::Time>,
+    author: Author,
+    content: String,
+}
+
+struct Author {
+    name: String,
+    email: String,
+}
+
+impl FeedEntry {
+    /// An entry for a commit. `repo_name` prefixes the title if the feed spans repositories.
+    fn for_commit(repo_url: &str, commit: &Commit<'_>, repo_name: Option<&str>) -> Self {
+        let url = format!("{}/commit/{}", repo_url, commit.id());
+        let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default());
+        Self {
+            id: url.clone(),
+            title: match repo_name {
+                Some(repo_name) => format!("{}: {}", repo_name, summary),
+                None => summary.into_owned(),
+            },
+            url,
+            updated: commit.committer().when(),
+            published: Some(commit.author().when()),
+            author: author(&commit.author()),
+            content: commit_message(commit),
+        }
+    }
+}
+
+impl Feed {
+    fn to_xml(&self) -> String {
+        // A feed without entries was last updated whenever it's asked for.
+        let updated = self
+            .entries
+            .iter()
+            .max_by_key(|entry| entry.updated.seconds())
+            .map(|entry| format_atom_time(entry.updated))
+            .unwrap_or_else(|| time::OffsetDateTime::now_utc().format(time::Format::Rfc3339));
+
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        xml.push_str(&format!("  <id>{}</id>\n", escape_html(&self.id)));
+        xml.push_str(&format!(
+            "  <title>{}</title>\n",
+            escape_xml_text(&self.title)
+        ));
+        xml.push_str(&format!("  <updated>{}</updated>\n", updated));
+        xml.push_str(&format!(
+            "  <link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>\n",
+            escape_html(&self.self_url)
+        ));
+        xml.push_str(&format!(
+            "  <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n",
+            escape_html(&self.alternate_url)
+        ));
+        xml.push_str("  <generator>sourceshack</generator>\n");
+        for entry in &self.entries {
+            xml.push_str("  <entry>\n");
+            xml.push_str(&format!("    <id>{}</id>\n", escape_html(&entry.id)));
+            xml.push_str(&format!(
+                "    <title>{}</title>\n",
+                escape_xml_text(&entry.title)
+            ));
+            xml.push_str(&format!(
+                "    <updated>{}</updated>\n",
+                format_atom_time(entry.updated)
+            ));
+            if let Some(published) = entry.published {
+                xml.push_str(&format!(
+                    "    <published>{}</published>\n",
+                    format_atom_time(published)
+                ));
+            }
+            xml.push_str(&format!(
+                "    <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n",
+                escape_html(&entry.url)
+            ));
+            xml.push_str("    <author>\n");
+            xml.push_str(&format!(
+                "      <name>{}</name>\n",
+                escape_xml_text(&entry.author.name)
+            ));
+            if !entry.author.email.is_empty() {
+                xml.push_str(&format!(
+                    "      <email>{}</email>\n",
+                    escape_xml_text(&entry.author.email)
+                ));
+            }
+            xml.push_str("    </author>\n");
+            xml.push_str(&format!(
+                "    <content type=\"text\">{}</content>\n",
+                escape_xml_text(&entry.content)
+            ));
+            xml.push_str("  </entry>\n");
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+}
+
+/// Escapes text for XML and drops the control characters XML 1.0 can't represent at all,
+/// which commit messages occasionally contain.
+fn escape_xml_text(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|&c| !c.is_control() || c == '\n' || c == '\t' || c == '\r')
+        .collect();
+    escape_html(&text)
+}
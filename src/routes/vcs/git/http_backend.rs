@@ -1,78 +1,231 @@
 use std::path::{Path, PathBuf};
 
+use log::error;
 use rocket::{
     data::ByteUnit,
     handler::{Handler, Outcome},
-    http::Method,
+    http::{Method, Status},
     Config, Data, Request, Response, Route, State,
 };
+use sqlx::types::Uuid;
 
-use crate::cgi::{CgiScript, CgiScriptError};
+use crate::{
+    auth::{
+        basic::{self, BasicCredentials},
+        password::check_credentials,
+        token::{self, Scope},
+        totp,
+    },
+    cgi::{
+        auth::Auth,
+        rocket::{outcome, CgiRequest},
+        CgiLimits,
+    },
+    db::Postgres,
+};
 
 #[derive(Clone, Debug)]
 pub struct GitHttpBackend {
     repo_dir: PathBuf,
+    limits: CgiLimits,
 }
 
 impl GitHttpBackend {
     pub fn new<P: AsRef<Path>>(repo_dir: P) -> Self {
         let repo_dir = repo_dir.as_ref().to_path_buf();
-        Self { repo_dir }
+        Self {
+            repo_dir,
+            limits: CgiLimits::default(),
+        }
+    }
+
+    /// Replaces the default limits on the `git http-backend` processes.
+    pub fn limits(self, limits: CgiLimits) -> Self {
+        Self { limits, ..self }
     }
 }
 
 #[async_trait::async_trait]
 impl Handler for GitHttpBackend {
     async fn handle<'r, 's: 'r>(&'s self, request: &'r Request<'_>, data: Data) -> Outcome<'r> {
-        // TODO: Handle the error case.
-        let config: State<Config> = request.guard().await.unwrap();
+        let config: State<Config> = match request.guard().await {
+            rocket::outcome::Outcome::Success(config) => config,
+            _ => return Outcome::Failure(Status::InternalServerError),
+        };
+
+        let remote_user = if is_push(request) {
+            authorize_push(request).await.map(Some)
+        } else {
+            authenticate_fetch(request).await
+        };
+        let remote_user = match remote_user {
+            Ok(remote_user) => remote_user,
+            Err(Status::Unauthorized) => {
+                return Outcome::Success(
+                    Response::build()
+                        .status(Status::Unauthorized)
+                        .raw_header("WWW-Authenticate", basic::CHALLENGE)
+                        .finalize(),
+                )
+            }
+            Err(status) => return Outcome::Failure(status),
+        };
 
         let mut request_path = request.uri().path().to_string();
         if !request_path.ends_with(".git") {
             request_path.push_str(".git");
         }
 
-        let data = {
-            let mut data = data.open(ByteUnit::max_value());
-            let mut buf = Vec::new();
-            tokio::io::copy(&mut data, &mut buf).await.map(|_| buf)
+        let path_translated = match translate_git_path(&self.repo_dir, request) {
+            Some(path_translated) => path_translated,
+            None => {
+                error!("{} is not valid UTF-8", self.repo_dir.display());
+                return Outcome::Failure(Status::InternalServerError);
+            }
         };
-        let response = data
-            .map_err(|err| CgiScriptError::Io(err))
-            .and_then(|data| {
-                CgiScript::new("git", &["http-backend"], &[])
-                    .server_software("rocket")
-                    .server_name(&config.address.to_string())
-                    .server_port(&config.port.to_string())
-                    .request_method(request.method().as_str())
-                    .query_string(request.uri().query().unwrap_or(""))
-                    .remote_addr(
-                        &request
-                            .client_ip()
-                            .map(|ip| ip.to_string())
-                            .unwrap_or_default(),
-                    )
-                    .path_info(&request_path)
-                    .path_translated(&translate_git_path(&self.repo_dir, request))
-                    .content_type(
-                        &request
-                            .content_type()
-                            .map(|ct| ct.to_string())
-                            .unwrap_or_default(),
-                    )
-                    .run(data.as_slice())
-                    .map(|response| {
-                        let response: Response = response.into();
-                        response
-                    })
-            });
-
-        Outcome::try_from(request, response)
+        let cgi_request = CgiRequest::new(request, &config);
+        // Every repository is public and access has been checked above, so http-backend
+        // doesn't need to look for git-daemon-export-ok.
+        let mut script = cgi_request
+            .script("git", &["http-backend"], &[("GIT_HTTP_EXPORT_ALL", "1")])
+            .path_info(&request_path)
+            .path_translated(&path_translated)
+            .limits(&self.limits);
+        if let Some(remote_user) = &remote_user {
+            script = script.remote_user(Auth::Basic, remote_user);
+        }
+        outcome(request, script.run(data.open(ByteUnit::max_value())).await)
     }
 }
 
-fn translate_git_path(repo_dir: &Path, request: &Request) -> String {
-    repo_dir
+/// Whether `request` is part of a push, i.e. either the ref advertisement for
+/// `git-receive-pack` or the `git-receive-pack` call itself.
+fn is_push(request: &Request<'_>) -> bool {
+    request.uri().path().ends_with("/git-receive-pack")
+        || request
+            .uri()
+            .query()
+            .map(|query| query.split('&').any(|pair| pair == "service=git-receive-pack"))
+            .unwrap_or(false)
+}
+
+/// Repositories are public, so fetching needs no credentials. Any that are sent anyway, e.g. by a
+/// credential helper, still have to be valid, and a personal access token has to have the
+/// `repo:read` scope.
+async fn authenticate_fetch(request: &Request<'_>) -> Result<Option<String>, Status> {
+    let credentials = match BasicCredentials::from_request(request) {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    let pg: Postgres = match request.guard().await {
+        rocket::outcome::Outcome::Success(pg) => pg,
+        _ => return Err(Status::InternalServerError),
+    };
+    let (_, username) = authenticate(pg, &credentials, Scope::RepoRead)
+        .await?
+        .ok_or(Status::Unauthorized)?;
+    Ok(Some(username))
+}
+
+/// Checks the HTTP Basic credentials of `request` and returns the username of the pushing user
+/// if they own the repository or are one of its collaborators. The password may also be a
+/// personal access token with the `repo:write` scope.
+///
+/// Returns `Status::Unauthorized` when the credentials are missing or wrong, so the caller can
+/// send a challenge, and `Status::Forbidden` when the user may not push to the repository.
+async fn authorize_push(request: &Request<'_>) -> Result<String, Status> {
+    let credentials = BasicCredentials::from_request(request).ok_or(Status::Unauthorized)?;
+    let (owner, repo) = repo_owner_and_name(request).ok_or(Status::NotFound)?;
+    let pg: Postgres = match request.guard().await {
+        rocket::outcome::Outcome::Success(pg) => pg,
+        _ => return Err(Status::InternalServerError),
+    };
+
+    let (userid, username) = authenticate(pg, &credentials, Scope::RepoWrite)
+        .await?
+        .ok_or(Status::Unauthorized)?;
+
+    let may_push = sqlx::query!(
+        r#"
+        SELECT EXISTS(
+            SELECT
+                1
+            FROM
+                public.repositories
+            INNER JOIN
+                public.users AS owners ON owners.userid = repositories.owner_id
+            LEFT JOIN
+                public.collaborators ON collaborators.repo_id = repositories.repo_id
+            WHERE
+                owners.username = $1
+                AND repositories.repo_name = $2
+                AND (repositories.owner_id = $3 OR collaborators.userid = $3)
+        ) AS "may_push!"
+        "#,
+        owner,
+        repo,
+        userid,
+    )
+    .fetch_one(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for push access to {}/{}: {}", owner, repo, err);
+        Status::InternalServerError
+    })?
+    .may_push;
+
+    if may_push {
+        Ok(username)
+    } else {
+        Err(Status::Forbidden)
+    }
+}
+
+/// Checks `credentials` as either a username and password or a username and personal access
+/// token. Tokens lacking `scope` are rejected with `Status::Forbidden`, and so are passwords of
+/// users with two-factor authentication enabled, who have to use a token instead.
+async fn authenticate<'r>(
+    pg: Postgres<'r>,
+    credentials: &BasicCredentials,
+    scope: Scope,
+) -> Result<Option<(Uuid, String)>, Status> {
+    if token::looks_like_token(&credentials.password) {
+        match token::authenticate(pg, &credentials.username, &credentials.password).await? {
+            Some((userid, scopes)) if scopes.contains(&scope) => {
+                Ok(Some((userid, credentials.username.clone())))
+            }
+            Some(_) => Err(Status::Forbidden),
+            None => Ok(None),
+        }
+    } else {
+        let user = check_credentials(pg, &credentials.username, &credentials.password).await?;
+        if let Some((userid, _)) = &user {
+            let secret = totp::secret_for_userid(pg, *userid).await.map_err(|err| {
+                error!("Could not query for TOTP secret of {}: {}", userid, err);
+                Status::InternalServerError
+            })?;
+            if secret.is_some() {
+                return Err(Status::Forbidden);
+            }
+        }
+        Ok(user)
+    }
+}
+
+/// Extracts the owner's username and the repository name from a git HTTP request path.
+fn repo_owner_and_name<'r>(request: &'r Request<'_>) -> Option<(&'r str, &'r str)> {
+    let mut segments = request.uri().segments();
+    let owner = segments.next()?;
+    let repo = segments.next()?;
+    let owner = owner.strip_prefix('~').unwrap_or(owner);
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+    Some((owner, repo))
+}
+
+/// The path of the repository a request is for, or `None` if it can't be passed on as
+/// PATH_TRANSLATED because `repo_dir` isn't valid UTF-8.
+fn translate_git_path(repo_dir: &Path, request: &Request) -> Option<String> {
+    let path = repo_dir
         .join(
             request
                 .uri()
@@ -85,16 +238,21 @@ fn translate_git_path(repo_dir: &Path, request: &Request) -> String {
                         #[cfg(not(windows))]
                         path.push('/');
                     }
-                    path.push_str(segment);
+                    if i == 0 {
+                        // Repositories are stored below the bare username, without the '~'.
+                        path.push_str(segment.strip_prefix('~').unwrap_or(segment));
+                    } else {
+                        path.push_str(segment);
+                    }
                     if i == 1 && !path.ends_with(".git") {
                         path.push_str(".git");
                     }
                     path
                 }),
         )
-        .to_str()
-        .unwrap()
-        .replace('\\', "/")
+        .to_str()?
+        .replace('\\', "/");
+    Some(path)
 }
 
 impl Into<Vec<Route>> for GitHttpBackend {
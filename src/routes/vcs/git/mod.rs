@@ -1,2 +1,186 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use git2::{BranchType, Object, ObjectType, Oid, Repository};
+use log::warn;
+use rocket::http::Status;
+
+use crate::{guards::UserNameGuard, util::ensure_correct_path_separator};
+
+pub mod archive;
+pub mod blame;
+pub mod commit;
+pub mod commit_log;
+pub mod compare;
+pub mod diff;
+pub mod error;
+pub mod feed;
 pub mod http_backend;
+pub mod patch;
+pub mod raw;
+pub mod readme;
+pub mod refs;
 pub mod web;
+
+/// The directory everything sourceshack keeps on disk is stored below.
+pub fn data_dir() -> PathBuf {
+    PathBuf::from(ensure_correct_path_separator(
+        env::var("SOURCESHACK_DATA_DIR").expect("SOURCESHACK_DATA_DIR is not set"),
+    ))
+}
+
+/// The directory holding the bare repositories of every user.
+pub fn git_repos_dir() -> PathBuf {
+    data_dir().join("git_repos")
+}
+
+/// Location of the bare repository `repo` owned by `owner` below `repo_dir`.
+pub fn repository_path<P: AsRef<Path>>(repo_dir: P, owner: &str, repo: &str) -> PathBuf {
+    repo_dir.as_ref().join(owner).join(format!("{}.git", repo))
+}
+
+pub fn open_repository(owner: UserNameGuard<'_>, repo: &str) -> Result<Repository, Status> {
+    let repo_dir = repository_path(git_repos_dir(), owner.as_ref(), repo);
+    Repository::open_bare(repo_dir).map_err(|err| {
+        if err.code() == git2::ErrorCode::NotFound {
+            Status::NotFound
+        } else {
+            warn!("Error in {}/{}: {}", owner, repo, err);
+            Status::InternalServerError
+        }
+    })
+}
+
+/// Resolves a branch, tag or full or abbreviated commit id to the id of the commit it points at.
+///
+/// Only names below `refs/heads/` and `refs/tags/` and hexadecimal ids are looked up, rather
+/// than the whole revision syntax, which would let anyone search the history with `:/<regex>`
+/// or read the reflog with `@{...}`.
+pub fn resolve_commit(repository: &Repository, rev: &str) -> Result<Oid, Status> {
+    let not_found_or_internal = |err: git2::Error| match err.code() {
+        git2::ErrorCode::NotFound
+        | git2::ErrorCode::InvalidSpec
+        | git2::ErrorCode::Ambiguous
+        | git2::ErrorCode::Peel => Status::NotFound,
+        _ => {
+            warn!("Could not resolve {}: {}", rev, err);
+            Status::InternalServerError
+        }
+    };
+    find_rev(repository, rev)
+        .map_err(not_found_or_internal)?
+        .ok_or(Status::NotFound)?
+        .peel_to_commit()
+        .map(|commit| commit.id())
+        .map_err(not_found_or_internal)
+}
+
+fn find_rev<'repo>(
+    repository: &'repo Repository,
+    rev: &str,
+) -> Result<Option<Object<'repo>>, git2::Error> {
+    let names = if rev.starts_with("refs/heads/") || rev.starts_with("refs/tags/") {
+        vec![rev.to_string()]
+    } else {
+        vec![format!("refs/heads/{}", rev), format!("refs/tags/{}", rev)]
+    };
+    for name in &names {
+        match repository.find_reference(name) {
+            Ok(reference) => return reference.peel(ObjectType::Any).map(Some),
+            Err(err)
+                if err.code() == git2::ErrorCode::NotFound
+                    || err.code() == git2::ErrorCode::InvalidSpec => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    if rev.len() < 4 || rev.len() > 40 || !rev.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    if rev.len() == 40 {
+        repository.find_object(Oid::from_str(rev)?, None).map(Some)
+    } else {
+        // Nothing but hex digits is always read as an abbreviated object id.
+        repository.revparse_single(rev).map(Some)
+    }
+}
+
+/// Resolves the branch to display: `primary_branch` if it exists, otherwise whatever the bare
+/// repository's HEAD points at. Returns `None` if the repository has no commits to show.
+pub fn primary_branch_tip(
+    repository: &Repository,
+    primary_branch: Option<&str>,
+) -> Option<(String, Oid)> {
+    if let Some(primary_branch) = primary_branch {
+        if let Some(target) = repository
+            .find_branch(primary_branch, BranchType::Local)
+            .ok()
+            .and_then(|branch| branch.get().target())
+        {
+            return Some((primary_branch.to_string(), target));
+        }
+    }
+    let head = repository.head().ok()?;
+    Some((
+        String::from_utf8_lossy(head.shorthand_bytes()).into_owned(),
+        head.target()?,
+    ))
+}
+
+/// The kind of object found at `path` in the tree of `commit_id`, or `None` if there is
+/// nothing there. The empty path is the root tree.
+pub fn tree_entry_kind_at(
+    repository: &Repository,
+    commit_id: Oid,
+    path: &Path,
+) -> Result<Option<ObjectType>, Status> {
+    if path == Path::new("") {
+        return Ok(Some(ObjectType::Tree));
+    }
+    let tree = repository
+        .find_commit(commit_id)
+        .and_then(|commit| commit.tree())
+        .map_err(|err| {
+            warn!("Could not read tree of {}: {}", commit_id, err);
+            Status::InternalServerError
+        })?;
+    match tree.get_path(path) {
+        Ok(entry) => Ok(entry.kind()),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(err) => {
+            warn!("Could not look up {} in {}: {}", path.display(), commit_id, err);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Joins the components of `path` with '/', regardless of the platform's separator.
+pub fn path_to_string(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Roughly how long ago `time` was, e.g. "3 days ago".
+pub fn format_age(time: git2::Time) -> String {
+    let seconds = (time::OffsetDateTime::now_utc().unix_timestamp() - time.seconds()).max(0);
+    let (count, unit) = match seconds {
+        seconds if seconds < 60 => return "just now".to_string(),
+        seconds if seconds < 60 * 60 => (seconds / 60, "minute"),
+        seconds if seconds < 24 * 60 * 60 => (seconds / (60 * 60), "hour"),
+        seconds if seconds < 30 * 24 * 60 * 60 => (seconds / (24 * 60 * 60), "day"),
+        seconds if seconds < 365 * 24 * 60 * 60 => (seconds / (30 * 24 * 60 * 60), "month"),
+        seconds => (seconds / (365 * 24 * 60 * 60), "year"),
+    };
+    format!("{} {}{} ago", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Formats a commit or signature time in the time zone it was recorded in.
+pub fn format_git_time(time: git2::Time) -> String {
+    time::OffsetDateTime::from_unix_timestamp(time.seconds())
+        .to_offset(time::UtcOffset::minutes(time.offset_minutes() as i16))
+        .format("%Y-%m-%d %H:%M %z")
+}
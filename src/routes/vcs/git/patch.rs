@@ -0,0 +1,193 @@
+use git2::{Commit, Diff, DiffFormat, Oid, Repository, Sort};
+use rocket::{
+    get,
+    http::{ContentType, RawStr},
+    request::FromParam,
+    response::content::Content,
+    routes, Route,
+};
+
+use crate::guards::{AaudStr, UserNameGuard};
+
+use super::{commit::find_commit, diff, error::GitWebError, open_repository, resolve_commit};
+
+pub fn routes() -> Vec<Route> {
+    routes![commit_patch, compare_patch]
+}
+
+/// `git format-patch` refuses to put more commits than this into one series, and so do we.
+const MAX_SERIES_LEN: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PatchFormat {
+    /// An mbox of `git format-patch` style emails, suitable for `git am`.
+    Patch,
+    /// A plain unified diff, suitable for `git apply`.
+    Diff,
+}
+
+/// A path segment of the form `<rev>.patch` or `<rev>.diff`. Anything else is forwarded, so
+/// that the HTML views can be mounted on the same paths with a lower rank.
+#[derive(Clone, Debug)]
+struct PatchRequest {
+    rev: String,
+    format: PatchFormat,
+}
+
+impl<'a> FromParam<'a> for PatchRequest {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        let decoded = param.percent_decode().map_err(|_| param)?;
+        let (rev, format) = if let Some(rev) = decoded.strip_suffix(".patch") {
+            (rev, PatchFormat::Patch)
+        } else if let Some(rev) = decoded.strip_suffix(".diff") {
+            (rev, PatchFormat::Diff)
+        } else {
+            return Err(param);
+        };
+        if rev.is_empty() {
+            return Err(param);
+        }
+        Ok(Self {
+            rev: rev.to_string(),
+            format,
+        })
+    }
+}
+
+#[get("/<owner>/<repo>/commit/<request>")]
+fn commit_patch(
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    request: PatchRequest,
+) -> Result<Content<Vec<u8>>, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit = find_commit(&repository, &request.rev)?;
+    let output = match request.format {
+        PatchFormat::Patch => format_patch_series(&repository, &[commit])?,
+        PatchFormat::Diff => {
+            let parent_tree = commit_parent_tree(&commit)?;
+            let diff = patch_diff(&repository, parent_tree.as_ref(), &commit.tree()?)?;
+            print_diff(&diff)?
+        }
+    };
+    Ok(Content(ContentType::Plain, output))
+}
+
+/// `<base>..<head>.patch` yields the commits reachable from head but not from base as a
+/// patch series, oldest first. `<base>..<head>.diff` yields the diff between the two trees.
+#[get("/<owner>/<repo>/compare/<request>")]
+fn compare_patch(
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+    request: PatchRequest,
+) -> Result<Content<Vec<u8>>, GitWebError> {
+    let mut revs = request.rev.splitn(2, "..");
+    let (base, head) = match (revs.next(), revs.next()) {
+        (Some(base), Some(head)) if !base.is_empty() && !head.is_empty() => (base, head),
+        _ => return Err(GitWebError::NotFound),
+    };
+    // Three dots are the HTML compare view, which compares against the merge base.
+    if head.starts_with('.') {
+        return Err(GitWebError::NotFound);
+    }
+
+    let repository = open_repository(owner, repo.as_ref())?;
+    let base = resolve_commit(&repository, base)?;
+    let head = resolve_commit(&repository, head)?;
+    let output = match request.format {
+        PatchFormat::Patch => {
+            let commits = commits_between(&repository, base, head)?;
+            if commits.len() > MAX_SERIES_LEN {
+                return Err(GitWebError::TooLarge);
+            }
+            format_patch_series(&repository, &commits)?
+        }
+        PatchFormat::Diff => {
+            let base_tree = repository.find_commit(base)?.tree()?;
+            let head_tree = repository.find_commit(head)?.tree()?;
+            print_diff(&patch_diff(&repository, Some(&base_tree), &head_tree)?)?
+        }
+    };
+    Ok(Content(ContentType::Plain, output))
+}
+
+/// The non-merge commits reachable from `head` but not from `base`, oldest first.
+fn commits_between(
+    repository: &Repository,
+    base: Oid,
+    head: Oid,
+) -> Result<Vec<Commit<'_>>, git2::Error> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    revwalk.push(head)?;
+    revwalk.hide(base)?;
+    let mut commits = Vec::new();
+    for id in revwalk {
+        let commit = repository.find_commit(id?)?;
+        if commit.parent_count() <= 1 {
+            commits.push(commit);
+        }
+    }
+    Ok(commits)
+}
+
+fn commit_parent_tree<'repo>(
+    commit: &Commit<'repo>,
+) -> Result<Option<git2::Tree<'repo>>, git2::Error> {
+    match commit.parent(0) {
+        Ok(parent) => parent.tree().map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Diffs two trees for machine consumption. A patch that is missing files can't be applied, so
+/// instead of collapsing files like the rendered diffs do, a diff beyond the same limits is
+/// refused as a whole.
+fn patch_diff<'repo>(
+    repository: &'repo Repository,
+    old_tree: Option<&git2::Tree<'repo>>,
+    new_tree: &git2::Tree<'repo>,
+) -> Result<Diff<'repo>, GitWebError> {
+    let mut diff =
+        repository.diff_tree_to_tree(old_tree, Some(new_tree), Some(&mut diff::diff_options()))?;
+    if diff::exceeds_limits(&diff)? {
+        return Err(GitWebError::TooLarge);
+    }
+    diff::find_renames(&mut diff)?;
+    Ok(diff)
+}
+
+/// Formats `commits` as consecutive `git format-patch` emails, numbered `[PATCH n/m]` when
+/// there is more than one.
+fn format_patch_series(
+    repository: &Repository,
+    commits: &[Commit<'_>],
+) -> Result<Vec<u8>, GitWebError> {
+    let mut output = Vec::new();
+    for (idx, commit) in commits.iter().enumerate() {
+        let parent_tree = commit_parent_tree(commit)?;
+        let mut diff = patch_diff(repository, parent_tree.as_ref(), &commit.tree()?)?;
+        let email = diff.format_email(idx + 1, commits.len(), commit, None)?;
+        output.extend_from_slice(&email);
+        // git am expects a "-- " signature separator, a version line and an empty line between
+        // emails.
+        output.extend_from_slice(b"-- \nsourceshack\n\n");
+    }
+    Ok(output)
+}
+
+/// Prints `diff` the way `git diff` does.
+fn print_diff(diff: &Diff<'_>) -> Result<Vec<u8>, git2::Error> {
+    let mut output = Vec::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        match line.origin() {
+            origin @ '+' | origin @ '-' | origin @ ' ' => output.push(origin as u8),
+            _ => {}
+        }
+        output.extend_from_slice(line.content());
+        true
+    })?;
+    Ok(output)
+}
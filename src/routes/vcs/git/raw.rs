@@ -0,0 +1,261 @@
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use git2::{ObjectType, Oid, Repository};
+use log::{error, warn};
+use rocket::{
+    get,
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    routes, Request, Response, Route,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    runtime::Handle,
+    sync::oneshot,
+};
+
+use crate::guards::{AaudStr, UserNameGuard};
+
+use super::{error::GitWebError, git_repos_dir, open_repository, repository_path, resolve_commit};
+
+pub fn routes() -> Vec<Route> {
+    routes![raw_blob]
+}
+
+/// Size of the chunks a blob is streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// How much of a blob is inspected to tell binary files from text, the same amount git uses.
+const SNIFF_LEN: usize = 8000;
+
+#[get("/<owner>/<repo>/raw/<rev>/<path..>")]
+async fn raw_blob<'r>(
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    rev: String,
+    path: PathBuf,
+    if_none_match: IfNoneMatch<'_>,
+) -> Result<RawBlob, GitWebError> {
+    let (blob_id, size) = {
+        let repository = open_repository(owner, repo.as_ref())?;
+        let commit_id = resolve_commit(&repository, &rev)?;
+        let blob_id = blob_id_at(&repository, commit_id, &path)?;
+        let (size, _) = repository.odb()?.read_header(blob_id)?;
+        (blob_id, size)
+    };
+
+    let etag = format!("\"{}\"", blob_id);
+    if if_none_match.matches(&etag) {
+        return Ok(RawBlob::NotModified { etag });
+    }
+
+    let repo_dir = repository_path(git_repos_dir(), owner.as_ref(), repo.as_ref());
+    let (head, rest) = stream_blob(repo_dir, blob_id).await?;
+
+    let is_binary = head.contains(&0);
+    let content_type = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(ContentType::from_extension)
+        .map(|content_type| {
+            // Never let a browser render a file as a page on our origin.
+            if content_type == ContentType::HTML || content_type == ContentType::XML {
+                ContentType::Plain
+            } else {
+                content_type
+            }
+        })
+        .unwrap_or(if is_binary {
+            ContentType::Binary
+        } else {
+            ContentType::Plain
+        });
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().replace('"', ""))
+        .unwrap_or_default();
+
+    Ok(RawBlob::Content {
+        etag,
+        content_type,
+        content_disposition: if is_binary {
+            Some(format!("attachment; filename=\"{}\"", file_name))
+        } else {
+            None
+        },
+        size,
+        body: Box::pin(Cursor::new(head).chain(rest)),
+    })
+}
+
+fn blob_id_at(repository: &Repository, commit_id: Oid, path: &Path) -> Result<Oid, GitWebError> {
+    let tree = repository.find_commit(commit_id)?.tree()?;
+    match tree.get_path(path) {
+        Ok(entry) if entry.kind() == Some(ObjectType::Blob) => Ok(entry.id()),
+        Ok(_) => Err(GitWebError::NotFound),
+        Err(err) if err.code() == git2::ErrorCode::NotFound => Err(GitWebError::NotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the blob `blob_id` on a blocking thread. Returns its first `SNIFF_LEN` bytes, which
+/// are needed to pick the response headers, and a stream of the remaining bytes.
+///
+/// Loose objects are read from the object database incrementally. libgit2 can't do that for
+/// packed objects, so those are inflated in one go on the blocking thread instead.
+async fn stream_blob(
+    repo_dir: PathBuf,
+    blob_id: Oid,
+) -> Result<(Vec<u8>, impl AsyncRead + Send), Status> {
+    let (mut writer, reader) = tokio::io::duplex(CHUNK_SIZE);
+    let (head_sender, head_receiver) = oneshot::channel();
+    let handle = Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let repository = match Repository::open_bare(&repo_dir) {
+            Ok(repository) => repository,
+            Err(err) => {
+                warn!("Could not open {}: {}", repo_dir.display(), err);
+                let _ = head_sender.send(Err(Status::InternalServerError));
+                return;
+            }
+        };
+        let odb = match repository.odb() {
+            Ok(odb) => odb,
+            Err(err) => {
+                warn!("Could not open object database of {}: {}", repo_dir.display(), err);
+                let _ = head_sender.send(Err(Status::InternalServerError));
+                return;
+            }
+        };
+        let blob;
+        let mut source: Box<dyn Read> = match odb.reader(blob_id) {
+            // The reader claims to fill the whole buffer on every read, even past the end of
+            // the object, so it never signals the end by itself.
+            Ok((reader, size, _)) => Box::new(reader.take(size as u64)),
+            Err(_) => match repository.find_blob(blob_id) {
+                Ok(found) => {
+                    blob = found;
+                    Box::new(blob.content())
+                }
+                Err(err) => {
+                    warn!("Could not read {}: {}", blob_id, err);
+                    let _ = head_sender.send(Err(Status::InternalServerError));
+                    return;
+                }
+            },
+        };
+
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        if let Err(err) = (&mut source).take(SNIFF_LEN as u64).read_to_end(&mut head) {
+            warn!("Could not read {}: {}", blob_id, err);
+            let _ = head_sender.send(Err(Status::InternalServerError));
+            return;
+        }
+        if head_sender.send(Ok(head)).is_err() {
+            return;
+        }
+
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let len = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) => {
+                    error!("Could not read {}: {}", blob_id, err);
+                    break;
+                }
+            };
+            // The client hung up if this fails, so there is nobody left to send the rest to.
+            if handle.block_on(writer.write_all(&buf[..len])).is_err() {
+                break;
+            }
+        }
+    });
+
+    match head_receiver.await {
+        Ok(Ok(head)) => Ok((head, reader)),
+        Ok(Err(status)) => Err(status),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+enum RawBlob {
+    NotModified {
+        etag: String,
+    },
+    Content {
+        etag: String,
+        content_type: ContentType,
+        content_disposition: Option<String>,
+        size: usize,
+        body: Pin<Box<dyn AsyncRead + Send>>,
+    },
+}
+
+impl<'r> Responder<'r, 'static> for RawBlob {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            RawBlob::NotModified { etag } => Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .ok(),
+            RawBlob::Content {
+                etag,
+                content_type,
+                content_disposition,
+                size,
+                body,
+            } => {
+                let mut response = Response::build();
+                response
+                    .header(content_type)
+                    .raw_header("Content-Length", size.to_string())
+                    .raw_header("ETag", etag)
+                    .raw_header("X-Content-Type-Options", "nosniff")
+                    .raw_header(
+                        "Content-Security-Policy",
+                        "default-src 'none'; style-src 'unsafe-inline'; sandbox",
+                    )
+                    .streamed_body(body);
+                if let Some(content_disposition) = content_disposition {
+                    response.raw_header("Content-Disposition", content_disposition);
+                }
+                response.ok()
+            }
+        }
+    }
+}
+
+/// The entity tags listed in an `If-None-Match` header.
+struct IfNoneMatch<'a> {
+    value: Option<&'a str>,
+}
+
+impl<'a> IfNoneMatch<'a> {
+    fn matches(&self, etag: &str) -> bool {
+        self.value
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|candidate| candidate.trim())
+                    .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+            })
+            .unwrap_or(false)
+    }
+}
+
+#[async_trait::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch<'a> {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Self {
+            value: request.headers().get_one("If-None-Match"),
+        })
+    }
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+use git2::{ObjectType, Oid, Repository, Tree};
+use log::warn;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+use crate::markdown;
+
+/// The file names recognised as a README, in order of preference.
+const README_NAMES: &[&str] = &["README.md", "README", "README.rst", "README.txt"];
+/// READMEs larger than this are not shown.
+const MAX_README_SIZE: usize = 512 * 1024;
+
+/// Characters escaped in a single path segment of a URL.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Readme {
+    pub name: String,
+    /// The rendered README if it is Markdown.
+    pub html: Option<String>,
+    /// The README as it is otherwise.
+    pub text: Option<String>,
+}
+
+/// Where a README is found, which is needed to turn its relative links into links to the
+/// files they refer to.
+pub struct ReadmeLocation<'a> {
+    pub owner: &'a str,
+    pub repo: &'a str,
+    pub rev: &'a str,
+    /// The directory containing the README.
+    pub dir: &'a Path,
+}
+
+/// Looks for a README in the directory `location.dir` of the tree of `commit_id`.
+pub fn find_readme(
+    repository: &Repository,
+    commit_id: Oid,
+    location: &ReadmeLocation<'_>,
+) -> Option<Readme> {
+    let result = repository
+        .find_commit(commit_id)
+        .and_then(|commit| commit.tree())
+        .and_then(|root| {
+            let dir_tree = if location.dir == Path::new("") {
+                root.clone()
+            } else {
+                root.get_path(location.dir)?
+                    .to_object(repository)?
+                    .peel_to_tree()?
+            };
+            let entry = README_NAMES.iter().find_map(|readme_name| {
+                dir_tree.iter().find(|entry| {
+                    entry.kind() == Some(ObjectType::Blob)
+                        && entry
+                            .name()
+                            .map(|name| name.eq_ignore_ascii_case(readme_name))
+                            .unwrap_or(false)
+                })
+            });
+            let entry = match entry {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            let blob = repository.find_blob(entry.id())?;
+            if blob.size() > MAX_README_SIZE || blob.is_binary() {
+                return Ok(None);
+            }
+
+            let name = entry.name().unwrap_or_default().to_string();
+            let content = String::from_utf8_lossy(blob.content());
+            let readme = if is_markdown(&name) {
+                Readme {
+                    html: Some(markdown::render(&content, |url, is_image| {
+                        rewrite_url(&root, location, url, is_image)
+                    })),
+                    text: None,
+                    name,
+                }
+            } else {
+                Readme {
+                    html: None,
+                    text: Some(content.into_owned()),
+                    name,
+                }
+            };
+            Ok(Some(readme))
+        });
+    match result {
+        Ok(readme) => readme,
+        Err(err) => {
+            warn!(
+                "Could not look for a README in {} at {}: {}",
+                location.dir.display(),
+                commit_id,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn is_markdown(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".md") || name.ends_with(".markdown")
+}
+
+/// Points relative links at the blob or tree view of their target, and relative images at
+/// the raw file. Links outside of the repository are left alone.
+fn rewrite_url(
+    root: &Tree<'_>,
+    location: &ReadmeLocation<'_>,
+    url: &str,
+    is_image: bool,
+) -> Option<String> {
+    let has_scheme = url
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()
+        .map(|first| first.contains(':'))
+        .unwrap_or(false);
+    if url.is_empty() || url.starts_with('#') || url.starts_with("//") || has_scheme {
+        return None;
+    }
+
+    let (target, suffix) = url.split_at(url.find(|c| c == '?' || c == '#').unwrap_or(url.len()));
+    let mut segments: Vec<String> = if target.starts_with('/') {
+        Vec::new()
+    } else {
+        location
+            .dir
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect()
+    };
+    for segment in target.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                // Pointing above the root of the repository.
+                segments.pop()?;
+            }
+            segment => segments.push(percent_decode_str(segment).decode_utf8_lossy().into_owned()),
+        }
+    }
+
+    let is_tree = segments.is_empty()
+        || root
+            .get_path(Path::new(&segments.join("/")))
+            .map(|entry| entry.kind() == Some(ObjectType::Tree))
+            .unwrap_or(false);
+    let view = if is_image {
+        "raw"
+    } else if is_tree {
+        "tree"
+    } else {
+        "blob"
+    };
+    let mut rewritten = format!(
+        "/~{}/{}/{}/{}",
+        location.owner,
+        location.repo,
+        view,
+        utf8_percent_encode(location.rev, NON_ALPHANUMERIC)
+    );
+    for segment in &segments {
+        rewritten.push('/');
+        rewritten.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+    }
+    rewritten.push_str(suffix);
+    Some(rewritten)
+}
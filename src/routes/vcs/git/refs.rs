@@ -0,0 +1,172 @@
+use std::cmp::Ordering;
+
+use git2::{BranchType, Commit, ObjectType, Repository};
+use rocket::{get, routes, Route};
+use rocket_contrib::templates::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    guards::{AaudStr, UserNameGuard},
+};
+
+use super::{error::GitWebError, format_age, format_git_time, open_repository};
+
+pub fn routes() -> Vec<Route> {
+    routes![view_refs]
+}
+
+#[get("/<owner>/<repo>/refs")]
+fn view_refs(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'_>,
+    repo: AaudStr<'_>,
+) -> Result<Template, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let refs = list_refs(&repository)?;
+    let head = repository
+        .head()
+        .ok()
+        .map(|head| String::from_utf8_lossy(head.shorthand_bytes()).into_owned())
+        .unwrap_or_default();
+
+    let context = RefsInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        head,
+        branches: refs.branches,
+        tags: refs.tags,
+    };
+    Ok(Template::render("refs", context))
+}
+
+/// The branches and tags of a repository, in the order they are listed in.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RefList {
+    pub branches: Vec<RefInfo>,
+    pub tags: Vec<RefInfo>,
+}
+
+/// The names from a `RefList`, for switching between refs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RefNames {
+    pub branches: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl From<RefList> for RefNames {
+    fn from(from: RefList) -> Self {
+        Self {
+            branches: from
+                .branches
+                .into_iter()
+                .map(|branch| branch.name)
+                .collect(),
+            tags: from.tags.into_iter().map(|tag| tag.name).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RefInfo {
+    pub name: String,
+    pub commit_id: String,
+    pub short_id: String,
+    pub summary: String,
+    pub author: String,
+    pub date: String,
+    pub age: String,
+    /// The message of an annotated tag.
+    pub message: Option<String>,
+    #[serde(skip)]
+    time: i64,
+}
+
+impl RefInfo {
+    fn new(name: String, commit: &Commit<'_>, message: Option<String>) -> Self {
+        let id = commit.id().to_string();
+        Self {
+            name,
+            short_id: id[..7].to_string(),
+            commit_id: id,
+            summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default())
+                .into_owned(),
+            author: String::from_utf8_lossy(commit.author().name_bytes()).into_owned(),
+            date: format_git_time(commit.time()),
+            age: format_age(commit.time()),
+            message,
+            time: commit.time().seconds(),
+        }
+    }
+}
+
+/// Lists the local branches, most recently committed to first, and the tags that point at
+/// commits, highest version first. Tags that don't look like versions come last, newest first.
+pub fn list_refs(repository: &Repository) -> Result<RefList, git2::Error> {
+    let mut branches = Vec::new();
+    for branch in repository.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = String::from_utf8_lossy(branch.name_bytes()?).into_owned();
+        let commit = branch.get().peel_to_commit()?;
+        branches.push(RefInfo::new(name, &commit, None));
+    }
+    branches.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+
+    let mut tags = Vec::new();
+    for reference in repository.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        // Tags of trees and blobs have no history to show.
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        let name = String::from_utf8_lossy(reference.shorthand_bytes()).into_owned();
+        let message = match reference.peel(ObjectType::Tag) {
+            Ok(tag) => tag
+                .as_tag()
+                .and_then(|tag| tag.message_bytes())
+                .map(|message| String::from_utf8_lossy(message).trim().to_string()),
+            Err(_) => None,
+        };
+        tags.push(RefInfo::new(name, &commit, message));
+    }
+    tags.sort_by(|a, b| match (version_key(&a.name), version_key(&b.name)) {
+        (Some(a_version), Some(b_version)) => {
+            b_version.cmp(&a_version).then_with(|| b.time.cmp(&a.time))
+        }
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)),
+    });
+
+    Ok(RefList { branches, tags })
+}
+
+/// The version numbers in a tag name like `v1.2.3` or `release-2.0-rc1`, and whether it is a
+/// final release rather than a pre-release, which makes it sort after its pre-releases.
+fn version_key(name: &str) -> Option<(Vec<u64>, bool)> {
+    let start = name.find(|c: char| c.is_ascii_digit())?;
+    let version = &name[start..];
+    let end = version
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or_else(|| version.len());
+    let numbers = version[..end]
+        .trim_end_matches('.')
+        .split('.')
+        .map(|number| number.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    Some((numbers, end == version.len()))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RefsInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    head: String,
+    branches: Vec<RefInfo>,
+    tags: Vec<RefInfo>,
+}
@@ -1,32 +1,54 @@
-use std::{env, path::PathBuf};
+use std::path::{Path, PathBuf};
 
-use git2::{BranchType, Repository};
-use log::warn;
+use git2::{BranchType, ObjectType, Oid, Reference, Repository};
+use log::error;
 use rocket::{
     get,
     http::Status,
+    post,
+    request::{Form, FromForm},
     response::{Redirect, Responder},
     routes, Route,
 };
 use rocket_contrib::templates::Template;
 use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
 
 use crate::{
-    guards::{AaudStr, UserNameGuard},
-    util::ensure_correct_path_separator,
+    auth::{token::ApiUser, CurrentUser},
+    db::Postgres,
+    guards::{AaudStr, Host, UserNameGuard},
+    highlight,
+};
+
+use super::{
+    error::GitWebError,
+    open_repository, path_to_string, primary_branch_tip,
+    readme::{find_readme, Readme, ReadmeLocation},
+    refs::{list_refs, RefNames},
+    resolve_commit, tree_entry_kind_at,
 };
 
 use display_tree::{DisplayTree, FileMode};
 
 pub fn routes() -> Vec<Route> {
-    routes![view_repository]
+    routes![
+        view_repository,
+        view_tree_root,
+        view_tree,
+        view_blob,
+        set_primary_branch
+    ]
 }
 
 #[get("/<owner>/<repo>")]
-fn view_repository(
-    owner: UserNameGuard<'_>,
-    repo: AaudStr<'_>,
-) -> Result<Template, RedirectOrStatus> {
+async fn view_repository<'r>(
+    pg: Postgres<'r>,
+    current_user: Option<CurrentUser>,
+    host: Host<'_>,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+) -> Result<Template, RedirectOrError> {
     let repo = repo.to_string();
     if repo.ends_with(".git") {
         let mut repo = repo;
@@ -34,71 +56,446 @@ fn view_repository(
         return Err(Redirect::permanent(format!("/{}/{}", owner, repo)).into());
     }
 
-    let base = PathBuf::from(ensure_correct_path_separator(
-        env::var("SOURCESHACK_DATA_DIR").expect("SOURCESHACK_DATA_DIR is not set"),
-    ))
-    .join("git_repos");
-    let owner_dir = base.join(owner);
-    let mut repo_dir = owner_dir.join(&repo);
-    let mut ext = repo_dir.extension().unwrap_or_default().to_os_string();
-    ext.push("git");
-    repo_dir.set_extension(ext);
-
-    match Repository::open_bare(repo_dir) {
-        Ok(repository) => {
-            let master_branch = repository
-                .find_branch("master", BranchType::Local)
-                .expect("Could not find branch 'master'");
-            let reference = master_branch.get();
-            let branch_tip_commit_id = reference.target().unwrap();
-            let tree = DisplayTree::new("", &repository, branch_tip_commit_id)
-                .items
-                .into_iter()
-                .map(|item| {
-                    let kind = TreeEntryKind::from(item.filemode);
-                    let trimmed_commit_message = item.last_commit_message.trim();
-                    DisplayTreeEntry {
-                        name: item.name,
-                        icon: match kind {
-                            TreeEntryKind::File => "default_file".to_string(),
-                            TreeEntryKind::Directory => "default_folder".to_string(),
-                            TreeEntryKind::Symlink => "default_file".to_string(),
-                            TreeEntryKind::Gitlink => "file_type_git2".to_string(),
-                        },
-                        is_not_dir: kind != TreeEntryKind::Directory,
-                        commit_message: trimmed_commit_message
-                            .split("\n\n")
-                            .nth(0)
-                            .unwrap_or(trimmed_commit_message)
-                            .to_string(),
-                    }
-                })
-                .collect();
+    let row = repository_row(pg, owner.as_ref(), &repo).await?;
+    let is_owner = match (&current_user, &row) {
+        (Some(current_user), Some(row)) => current_user.userid == row.owner_id,
+        _ => false,
+    };
+    let current_user = current_user
+        .map(|current_user| current_user.username)
+        .unwrap_or_default();
+    let clone_url = format!("https://{}/~{}/{}", host, owner, repo);
+    let description = row
+        .as_ref()
+        .and_then(|row| row.repo_description.clone())
+        .unwrap_or_default();
 
-            let context = RepositoryInfo {
+    let repository = open_repository(owner, &repo)?;
+    let branches = local_branch_names(&repository);
+    let primary_branch = row.as_ref().map(|row| row.primary_branch.as_str());
+    let (branch, branch_tip_commit_id) = match primary_branch_tip(&repository, primary_branch) {
+        Some(tip) => tip,
+        None => {
+            let context = EmptyRepositoryInfo {
+                current_user,
                 owner: owner.as_ref(),
                 name: &repo,
-                tree,
+                description,
+                branch: primary_branch
+                    .map(str::to_string)
+                    .or_else(|| unborn_head_branch(&repository))
+                    .unwrap_or_default(),
+                branches,
+                is_owner,
+                clone_url,
             };
-            Ok(Template::render("repository", context))
+            return Ok(Template::render("repository_empty", context));
         }
-        Err(err) => {
-            if err.code() == git2::ErrorCode::NotFound {
-                dbg!();
-                Err(Status::NotFound.into())
-            } else {
-                warn!("Error in {}/{}: {}", owner, repo, err);
-                Err(Status::InternalServerError.into())
+    };
+
+    let readme = find_readme(
+        &repository,
+        branch_tip_commit_id,
+        &ReadmeLocation {
+            owner: owner.as_ref(),
+            repo: &repo,
+            rev: &branch,
+            dir: Path::new(""),
+        },
+    );
+    let context = RepositoryInfo {
+        current_user,
+        owner: owner.as_ref(),
+        name: &repo,
+        description,
+        rev: branch.clone(),
+        path: String::new(),
+        breadcrumbs: Vec::new(),
+        branch,
+        branches,
+        is_owner,
+        clone_url,
+        tree: display_tree_entries(&repository, Path::new(""), branch_tip_commit_id)?,
+        readme,
+        refs: list_refs(&repository).map_err(GitWebError::from)?.into(),
+    };
+    Ok(Template::render("repository", context))
+}
+
+#[get("/<owner>/<repo>/tree/<rev>")]
+async fn view_tree_root<'r>(
+    pg: Postgres<'r>,
+    current_user: Option<CurrentUser>,
+    host: Host<'_>,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    rev: String,
+) -> Result<Template, GitWebError> {
+    render_tree(pg, current_user, host, owner, repo, rev, PathBuf::new()).await
+}
+
+#[get("/<owner>/<repo>/tree/<rev>/<path..>", rank = 2)]
+async fn view_tree<'r>(
+    pg: Postgres<'r>,
+    current_user: Option<CurrentUser>,
+    host: Host<'_>,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    rev: String,
+    path: PathBuf,
+) -> Result<Template, GitWebError> {
+    render_tree(pg, current_user, host, owner, repo, rev, path).await
+}
+
+/// Renders the directory at `path` as of `rev`, which may be a branch, a tag or a commit id.
+async fn render_tree<'r>(
+    pg: Postgres<'r>,
+    current_user: Option<CurrentUser>,
+    host: Host<'_>,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    rev: String,
+    path: PathBuf,
+) -> Result<Template, GitWebError> {
+    let row = repository_row(pg, owner.as_ref(), repo.as_ref()).await?;
+    let current_user = current_user
+        .map(|current_user| current_user.username)
+        .unwrap_or_default();
+    let clone_url = format!("https://{}/~{}/{}", host, owner, repo);
+    let description = row
+        .as_ref()
+        .and_then(|row| row.repo_description.clone())
+        .unwrap_or_default();
+
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit_id = resolve_commit(&repository, &rev)?;
+    if tree_entry_kind_at(&repository, commit_id, &path)? != Some(ObjectType::Tree) {
+        return Err(GitWebError::NotFound);
+    }
+
+    let readme = find_readme(
+        &repository,
+        commit_id,
+        &ReadmeLocation {
+            owner: owner.as_ref(),
+            repo: repo.as_ref(),
+            rev: &rev,
+            dir: &path,
+        },
+    );
+    let context = RepositoryInfo {
+        current_user,
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        description,
+        path: path_to_string(&path),
+        breadcrumbs: breadcrumbs(&path),
+        branch: rev.clone(),
+        branches: local_branch_names(&repository),
+        rev,
+        is_owner: false,
+        clone_url,
+        tree: display_tree_entries(&repository, &path, commit_id)?,
+        readme,
+        refs: list_refs(&repository)?.into(),
+    };
+    Ok(Template::render("repository", context))
+}
+
+/// Blobs larger than this are not displayed inline at all.
+pub(super) const MAX_DISPLAYED_BLOB_SIZE: usize = 1024 * 1024;
+/// Blobs larger than this are displayed without syntax highlighting.
+pub(super) const MAX_HIGHLIGHTED_BLOB_SIZE: usize = 256 * 1024;
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "bmp", "ico", "svg"];
+
+#[get("/<owner>/<repo>/blob/<rev>/<path..>")]
+async fn view_blob<'r>(
+    current_user: Option<CurrentUser>,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    rev: String,
+    path: PathBuf,
+) -> Result<Template, GitWebError> {
+    let repository = open_repository(owner, repo.as_ref())?;
+    let commit_id = resolve_commit(&repository, &rev)?;
+    if tree_entry_kind_at(&repository, commit_id, &path)? != Some(ObjectType::Blob) {
+        return Err(GitWebError::NotFound);
+    }
+    let blob = repository
+        .find_commit(commit_id)
+        .and_then(|commit| commit.tree())
+        .and_then(|tree| tree.get_path(&path))
+        .and_then(|entry| entry.to_object(&repository))
+        .and_then(|object| object.peel_to_blob())?;
+
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let is_image = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+        .unwrap_or(false);
+    let (kind, lines) = if is_image {
+        (BlobKind::Image, Vec::new())
+    } else if blob.size() > MAX_DISPLAYED_BLOB_SIZE {
+        (BlobKind::TooLarge, Vec::new())
+    } else if blob.is_binary() {
+        (BlobKind::Binary, Vec::new())
+    } else {
+        let content = String::from_utf8_lossy(blob.content());
+        if blob.size() > MAX_HIGHLIGHTED_BLOB_SIZE {
+            (BlobKind::Text, highlight::plain_lines(&content))
+        } else {
+            (BlobKind::Text, highlight::highlight_lines(&file_name, &content))
+        }
+    };
+
+    let context = BlobInfo {
+        current_user: current_user
+            .map(|current_user| current_user.username)
+            .unwrap_or_default(),
+        owner: owner.as_ref(),
+        name: repo.as_ref(),
+        rev,
+        path: path_to_string(&path),
+        breadcrumbs: breadcrumbs(&path),
+        size: blob.size(),
+        kind,
+        lines,
+    };
+    Ok(Template::render("blob", context))
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BlobKind {
+    Text,
+    Image,
+    Binary,
+    TooLarge,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct BlobInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    rev: String,
+    path: String,
+    breadcrumbs: Vec<Breadcrumb>,
+    size: usize,
+    kind: BlobKind,
+    lines: Vec<String>,
+}
+
+fn display_tree_entries(
+    repository: &Repository,
+    path: &Path,
+    commit_id: Oid,
+) -> Result<Vec<DisplayTreeEntry>, GitWebError> {
+    Ok(DisplayTree::new(path, repository, commit_id)?
+        .items
+        .into_iter()
+        .map(|item| {
+            let kind = item
+                .filemode
+                .map(TreeEntryKind::from)
+                .unwrap_or(TreeEntryKind::Unknown);
+            let trimmed_commit_message = item.last_commit_message.trim();
+            DisplayTreeEntry {
+                name: item.name,
+                icon: match kind {
+                    TreeEntryKind::File => "default_file".to_string(),
+                    TreeEntryKind::Directory => "default_folder".to_string(),
+                    TreeEntryKind::Symlink => "default_file".to_string(),
+                    TreeEntryKind::Gitlink => "file_type_git2".to_string(),
+                    TreeEntryKind::Unknown => "default_file".to_string(),
+                },
+                is_not_dir: kind != TreeEntryKind::Directory,
+                is_submodule: kind == TreeEntryKind::Gitlink,
+                is_unknown: kind == TreeEntryKind::Unknown,
+                commit_message: trimmed_commit_message
+                    .split("\n\n")
+                    .nth(0)
+                    .unwrap_or(trimmed_commit_message)
+                    .to_string(),
             }
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Breadcrumb {
+    name: String,
+    path: String,
+}
+
+/// One breadcrumb per directory leading up to and including `path`.
+fn breadcrumbs(path: &Path) -> Vec<Breadcrumb> {
+    let mut breadcrumbs: Vec<Breadcrumb> = Vec::new();
+    for component in path.components() {
+        let name = component.as_os_str().to_string_lossy().into_owned();
+        let path = match breadcrumbs.last() {
+            Some(parent) => format!("{}/{}", parent.path, name),
+            None => name.clone(),
+        };
+        breadcrumbs.push(Breadcrumb { name, path });
+    }
+    breadcrumbs
+}
+
+/// Makes `branch` the primary branch of the repository, both in the database and as the HEAD
+/// of the bare repository, which is what `git clone` checks out.
+#[post("/<owner>/<repo>/settings/primary-branch", data = "<form>")]
+async fn set_primary_branch<'r>(
+    pg: Postgres<'r>,
+    current_user: ApiUser,
+    owner: UserNameGuard<'r>,
+    repo: AaudStr<'r>,
+    form: Form<PrimaryBranch>,
+) -> Result<Redirect, Status> {
+    let row = repository_row(pg, owner.as_ref(), repo.as_ref())
+        .await?
+        .ok_or(Status::NotFound)?;
+    if row.owner_id != current_user.userid {
+        return Err(Status::Forbidden);
+    }
+    let branch = form.branch.trim();
+    let reference = format!("refs/heads/{}", branch);
+    if !Reference::is_valid_name(&reference) {
+        return Err(Status::BadRequest);
+    }
+
+    {
+        let repository = open_repository(owner, repo.as_ref())?;
+        // An empty repository has no branches yet, so any name may be picked for the first one.
+        if !repository.is_empty().unwrap_or(false)
+            && repository.find_branch(branch, BranchType::Local).is_err()
+        {
+            return Err(Status::BadRequest);
         }
+        repository.set_head(&reference).map_err(|err| {
+            error!("Could not update HEAD of {}/{}: {}", owner, repo, err);
+            Status::InternalServerError
+        })?;
     }
+
+    sqlx::query!(
+        r#"
+        UPDATE public.repositories
+        SET primary_branch = $2
+        WHERE repo_id = $1"#,
+        row.repo_id,
+        branch,
+    )
+    .execute(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not update primary branch of {}/{}: {}", owner, repo, err);
+        Status::InternalServerError
+    })?;
+
+    Ok(Redirect::to(format!("/~{}/{}", owner, repo)))
+}
+
+#[derive(Debug, FromForm)]
+struct PrimaryBranch {
+    branch: String,
+}
+
+struct RepositoryRow {
+    repo_id: Uuid,
+    owner_id: Uuid,
+    primary_branch: String,
+    repo_description: Option<String>,
+}
+
+async fn repository_row<'r>(
+    pg: Postgres<'r>,
+    owner: &str,
+    repo: &str,
+) -> Result<Option<RepositoryRow>, Status> {
+    sqlx::query_as!(
+        RepositoryRow,
+        r#"
+        SELECT
+            repositories.repo_id,
+            repositories.owner_id,
+            repositories.primary_branch,
+            repositories.repo_description
+        FROM
+            public.repositories
+        INNER JOIN
+            public.users ON users.userid = repositories.owner_id
+        WHERE
+            users.username = $1 AND repositories.repo_name = $2
+        "#,
+        owner,
+        repo,
+    )
+    .fetch_optional(pg)
+    .await
+    .map_err(|err| {
+        error!("Could not query for repository {}/{}: {}", owner, repo, err);
+        Status::InternalServerError
+    })
+}
+
+/// The branch HEAD points at in a repository without commits.
+fn unborn_head_branch(repository: &Repository) -> Option<String> {
+    let head = repository.find_reference("HEAD").ok()?;
+    let target = head.symbolic_target()?;
+    target.strip_prefix("refs/heads/").map(str::to_string)
+}
+
+fn local_branch_names(repository: &Repository) -> Vec<String> {
+    repository
+        .branches(Some(BranchType::Local))
+        .map(|branches| {
+            branches
+                .filter_map(|branch| branch.ok())
+                .filter_map(|(branch, _)| {
+                    branch
+                        .name_bytes()
+                        .ok()
+                        .map(|name| String::from_utf8_lossy(name).into_owned())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct RepositoryInfo<'a> {
+    current_user: String,
     owner: &'a str,
     name: &'a str,
+    description: String,
+    rev: String,
+    path: String,
+    breadcrumbs: Vec<Breadcrumb>,
+    branch: String,
+    branches: Vec<String>,
+    is_owner: bool,
+    clone_url: String,
     tree: Vec<DisplayTreeEntry>,
+    readme: Option<Readme>,
+    refs: RefNames,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct EmptyRepositoryInfo<'a> {
+    current_user: String,
+    owner: &'a str,
+    name: &'a str,
+    description: String,
+    branch: String,
+    branches: Vec<String>,
+    is_owner: bool,
+    clone_url: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -106,6 +503,9 @@ struct DisplayTreeEntry {
     name: String,
     icon: String,
     is_not_dir: bool,
+    is_submodule: bool,
+    /// The entry has a mode git doesn't know, so there is no telling how to show it.
+    is_unknown: bool,
     commit_message: String,
 }
 
@@ -115,6 +515,7 @@ enum TreeEntryKind {
     Directory,
     Symlink,
     Gitlink,
+    Unknown,
 }
 
 impl From<FileMode> for TreeEntryKind {
@@ -129,30 +530,77 @@ impl From<FileMode> for TreeEntryKind {
 }
 
 #[derive(Debug, Responder)]
-pub enum RedirectOrStatus {
+pub enum RedirectOrError {
     Redirect(Redirect),
-    Status(Status),
+    Error(GitWebError),
 }
 
-impl From<Redirect> for RedirectOrStatus {
+impl From<Redirect> for RedirectOrError {
     fn from(from: Redirect) -> Self {
         Self::Redirect(from)
     }
 }
 
-impl From<Status> for RedirectOrStatus {
+impl From<GitWebError> for RedirectOrError {
+    fn from(from: GitWebError) -> Self {
+        Self::Error(from)
+    }
+}
+
+impl From<Status> for RedirectOrError {
     fn from(from: Status) -> Self {
-        Self::Status(from)
+        Self::Error(from.into())
     }
 }
 
 mod display_tree {
     use std::{
-        collections::HashMap,
-        path::{self, Path},
+        collections::{HashMap, VecDeque},
+        path::{self, Path, PathBuf},
+        sync::Mutex,
     };
 
-    use git2::{ObjectType, Oid, Repository, TreeEntry};
+    use git2::{ObjectType, Oid, Repository, Sort, Tree, TreeEntry};
+    use lazy_static::lazy_static;
+
+    use super::GitWebError;
+
+    /// Entries that haven't changed within this many commits are listed without a commit.
+    const MAX_WALKED_COMMITS: usize = 50_000;
+    /// The number of directory listings kept in `CACHE`.
+    const CACHE_CAPACITY: usize = 1024;
+
+    lazy_static! {
+        /// Listings keyed by the commit they were made for and the path of the directory.
+        ///
+        /// Commit ids are used rather than tree ids since the same tree can be reached through
+        /// different histories, while a commit id covers its entire history. That also makes
+        /// entries valid across every repository the commit is in.
+        static ref CACHE: Mutex<Cache> = Mutex::new(Cache::default());
+    }
+
+    #[derive(Default)]
+    struct Cache {
+        items: HashMap<(Oid, PathBuf), Vec<DisplayTreeItem>>,
+        order: VecDeque<(Oid, PathBuf)>,
+    }
+
+    impl Cache {
+        fn get(&self, key: &(Oid, PathBuf)) -> Option<Vec<DisplayTreeItem>> {
+            self.items.get(key).cloned()
+        }
+
+        fn insert(&mut self, key: (Oid, PathBuf), items: Vec<DisplayTreeItem>) {
+            if self.items.len() >= CACHE_CAPACITY {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.items.remove(&oldest);
+                }
+            }
+            if self.items.insert(key.clone(), items).is_none() {
+                self.order.push_back(key);
+            }
+        }
+    }
 
     #[derive(Debug)]
     pub struct DisplayTree {
@@ -160,10 +608,18 @@ mod display_tree {
     }
 
     impl DisplayTree {
-        /// # Panics
+        /// Lists the directory at `path` in the tree of `commit_id`, along with the most recent
+        /// commit that changed each entry.
         ///
-        /// This function will panice if `path` points to something other than a directory
-        pub fn new<P>(path: P, repository: &Repository, commit_id: Oid) -> Self
+        /// History is walked from `commit_id` until every entry is accounted for. An entry was
+        /// last changed by the first commit that has the entry's current version while none of
+        /// its parents do. Like `git log`, a merge that took the entry from one of its parents
+        /// is passed over in favour of the history of that parent.
+        pub fn new<P>(
+            path: P,
+            repository: &Repository,
+            commit_id: Oid,
+        ) -> Result<Self, GitWebError>
         where
             P: AsRef<Path>,
         {
@@ -176,95 +632,141 @@ mod display_tree {
                 path::Component::Normal(_) => false,
             });
             let normalized_path = invalid_prefix
-                .map(|invalid_prefix| path.strip_prefix(invalid_prefix).unwrap())
+                .and_then(|invalid_prefix| path.strip_prefix(invalid_prefix).ok())
                 .unwrap_or(path);
 
-            let mut revwalk = repository.revwalk().unwrap();
-            revwalk.push(commit_id).unwrap();
-
-            let mut oldest_commit_for_object = HashMap::new();
-
-            for older_commit_id in revwalk {
-                let older_commit_id = older_commit_id.unwrap();
-                let older_commit = repository.find_commit(older_commit_id).unwrap();
-                let older_tree = older_commit.tree().unwrap();
-                let older_target_tree = {
-                    if normalized_path == Path::new("") {
-                        older_tree
-                    } else {
-                        older_tree
-                            .get_path(normalized_path)
-                            .unwrap()
-                            .to_object(repository)
-                            .unwrap()
-                            .peel_to_tree()
-                            .unwrap()
-                    }
-                };
-                for entry in older_target_tree.iter() {
-                    if entry.kind() == Some(ObjectType::Blob)
-                        || entry.kind() == Some(ObjectType::Tree)
-                    {
-                        oldest_commit_for_object.insert(entry.id(), older_commit_id);
-                    }
-                }
+            let key = (commit_id, normalized_path.to_path_buf());
+            if let Some(items) = CACHE.lock().ok().and_then(|cache| cache.get(&key)) {
+                return Ok(Self { items });
             }
 
-            let commit = repository.find_commit(commit_id).unwrap();
-            let tree = commit.tree().unwrap();
-
-            let target_tree = {
-                if normalized_path == Path::new("") {
-                    tree
-                } else {
-                    tree.get_path(normalized_path)
-                        .unwrap()
-                        .to_object(repository)
-                        .unwrap()
-                        .peel_to_tree()
-                        .unwrap()
+            let commit = repository.find_commit(commit_id)?;
+            let target_tree = subtree(repository, &commit.tree()?, normalized_path)?
+                .ok_or(GitWebError::NotFound)?;
+            let entries: Vec<TreeEntry<'static>> =
+                target_tree.iter().map(|entry| entry.to_owned()).collect();
+            let mut last_commit_ids = vec![None; entries.len()];
+            let mut unresolved = entries.len();
+
+            let mut revwalk = repository.revwalk()?;
+            revwalk.set_sorting(Sort::TIME)?;
+            revwalk.push(commit_id)?;
+            for older_commit_id in revwalk.take(MAX_WALKED_COMMITS) {
+                if unresolved == 0 {
+                    break;
+                }
+                let older_commit_id = older_commit_id?;
+                let older_commit = repository.find_commit(older_commit_id)?;
+                // The directory may not have existed yet this far back in history.
+                let older_tree =
+                    match subtree(repository, &older_commit.tree()?, normalized_path)? {
+                        Some(tree) => tree,
+                        None => continue,
+                    };
+                let parent_trees = older_commit
+                    .parents()
+                    .map(|parent| subtree(repository, &parent.tree()?, normalized_path))
+                    .collect::<Result<Vec<_>, git2::Error>>()?;
+                // Nothing in the directory was changed relative to that parent.
+                if parent_trees
+                    .iter()
+                    .flatten()
+                    .any(|parent_tree| parent_tree.id() == older_tree.id())
+                {
+                    continue;
                 }
-            };
 
-            let items = target_tree
-                .iter()
-                .filter_map(|entry| {
-                    if entry.kind() == Some(ObjectType::Blob)
-                        || entry.kind() == Some(ObjectType::Tree)
+                for (entry, last_commit_id) in entries.iter().zip(&mut last_commit_ids) {
+                    if last_commit_id.is_some()
+                        || entry_id(&older_tree, entry.name_bytes()) != Some(entry.id())
                     {
-                        Some(DisplayTreeItem::new(
-                            repository,
-                            *oldest_commit_for_object.get(&entry.id()).unwrap(),
-                            entry,
-                        ))
-                    } else {
-                        None
+                        continue;
+                    }
+                    let in_parent = parent_trees.iter().flatten().any(|parent_tree| {
+                        entry_id(parent_tree, entry.name_bytes()) == Some(entry.id())
+                    });
+                    if !in_parent {
+                        *last_commit_id = Some(older_commit_id);
+                        unresolved -= 1;
                     }
+                }
+            }
+
+            let items = entries
+                .iter()
+                .zip(last_commit_ids)
+                .map(|(entry, last_commit_id)| {
+                    DisplayTreeItem::new(repository, last_commit_id, entry)
                 })
-                .collect();
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Ok(mut cache) = CACHE.lock() {
+                cache.insert(key, items.clone());
+            }
+            Ok(Self { items })
+        }
+    }
+
+    /// The directory at `path` below `root`, or `None` if there is no directory there.
+    fn subtree<'repo>(
+        repository: &'repo Repository,
+        root: &Tree<'repo>,
+        path: &Path,
+    ) -> Result<Option<Tree<'repo>>, git2::Error> {
+        if path == Path::new("") {
+            return Ok(Some(root.clone()));
+        }
+        match root.get_path(path) {
+            Ok(entry) if entry.kind() == Some(ObjectType::Tree) => {
+                repository.find_tree(entry.id()).map(Some)
+            }
+            Ok(_) => Ok(None),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-            Self { items }
+    fn entry_id(tree: &Tree<'_>, name: &[u8]) -> Option<Oid> {
+        match std::str::from_utf8(name) {
+            Ok(name) => tree.get_name(name).map(|entry| entry.id()),
+            Err(_) => tree
+                .iter()
+                .find(|entry| entry.name_bytes() == name)
+                .map(|entry| entry.id()),
         }
     }
 
     // TODO: !
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct DisplayTreeItem {
         pub name: String,
-        pub last_commit_id: Oid,
+        /// `None` if the entry hasn't changed within `MAX_WALKED_COMMITS` commits.
+        pub last_commit_id: Option<Oid>,
         pub last_commit_message: String,
-        pub filemode: FileMode,
+        /// `None` if the entry has a mode git doesn't know.
+        pub filemode: Option<FileMode>,
     }
 
     impl DisplayTreeItem {
-        fn new(repository: &Repository, last_commit_id: Oid, entry: TreeEntry<'_>) -> Self {
-            let last_commit = repository.find_commit(last_commit_id).unwrap();
-            Self {
-                name: entry.name().unwrap().to_string(),
+        fn new(
+            repository: &Repository,
+            last_commit_id: Option<Oid>,
+            entry: &TreeEntry<'_>,
+        ) -> Result<Self, GitWebError> {
+            let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+            let filemode = FileMode::from_i32(entry.filemode());
+            let last_commit_message = match last_commit_id {
+                Some(last_commit_id) => {
+                    let last_commit = repository.find_commit(last_commit_id)?;
+                    String::from_utf8_lossy(last_commit.message_bytes()).into_owned()
+                }
+                None => String::new(),
+            };
+            Ok(Self {
+                name,
                 last_commit_id,
-                last_commit_message: last_commit.message().unwrap().to_string(),
-                filemode: FileMode::from_i32(entry.filemode()).unwrap(),
-            }
+                last_commit_message,
+                filemode,
+            })
         }
     }
 
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, path::PathBuf};
 
 pub fn ensure_correct_path_separator(string: String) -> String {
     if std::path::MAIN_SEPARATOR != '/' {
@@ -12,6 +12,51 @@ pub fn read_expected_env_var(name: &str) -> String {
     env::var(name).unwrap_or_else(|err| panic!("{} could not be read: {}", name, err))
 }
 
+/// Reads a limit that may be unset, in which case the default applies, or set to `unlimited`.
+pub fn read_limit_env_var(name: &str) -> Option<Option<u64>> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return None,
+        Err(err) => panic!("{} could not be read: {}", name, err),
+    };
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("unlimited") {
+        return Some(None);
+    }
+    match value.parse() {
+        Ok(limit) => Some(Some(limit)),
+        Err(_) => panic!("{}: {:?} is neither a number nor unlimited", name, value),
+    }
+}
+
+/// Reads a list of CGI scripts to serve, as `<base>=<script path>` pairs separated by ';',
+/// e.g. `/cgit=/usr/lib/cgit/cgit.cgi;/hg=/srv/hgweb/hgweb.cgi`. The list may be unset.
+pub fn read_cgi_scripts_env_var(name: &str) -> Vec<(String, PathBuf)> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return Vec::new(),
+        Err(err) => panic!("{} could not be read: {}", name, err),
+    };
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let idx = entry
+                .find('=')
+                .unwrap_or_else(|| panic!("{}: {:?} is not <base>=<script path>", name, entry));
+            let (base, script_path) = (entry[..idx].trim(), entry[idx + 1..].trim());
+            if !base.starts_with('/') || script_path.is_empty() {
+                panic!("{}: {:?} is not <base>=<script path>", name, entry);
+            }
+            (
+                base.to_string(),
+                PathBuf::from(ensure_correct_path_separator(script_path.to_string())),
+            )
+        })
+        .collect()
+}
+
 pub fn tera_dummy_ctx() -> HashMap<(), ()> {
     HashMap::default()
 }